@@ -0,0 +1,212 @@
+use chrono::{Local, TimeZone};
+use num_format::Locale;
+use std::io;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, timeout};
+
+use crate::{
+    Direction,
+    auth::Key,
+    client,
+    net::{AddressFamily, BusyPolicy, ConnectOptions, NetConnector},
+    protocol::{Handshake, Request, RunRequest, Transport, client_handshake, new_session_id, read_line},
+    quic,
+    sink::Measurement,
+    tls::{self, TlsClientOptions, Verification},
+    transport::Connector,
+    utils::{format_number, print_statistics_terminal},
+};
+
+/// Time the agent gets beyond the test duration to connect and report back.
+const RESULT_GRACE: Duration = Duration::from_secs(60);
+
+/// Agent side of a run command: tests against the target like `client` does. The agent answers
+/// challenges of the target with its own session key, agents of one deployment share it.
+/// Server certificates are checked as `verification` says.
+pub async fn run_test(request: &Request, run: &RunRequest, key: Option<Key>, verification: &Verification) -> io::Result<Option<Measurement>> {
+    let options = ConnectOptions {
+        family: AddressFamily::Any,
+        bind: None,
+        interface: None,
+        tls: match run.transport {
+            Transport::Tls => Some(TlsClientOptions {
+                config: tls::client_config(verification)?,
+                server_name: None,
+            }),
+            _ => None,
+        },
+        quic: match run.transport {
+            Transport::Quic => Some(quic::client_config(verification, None)?),
+            _ => None,
+        },
+        psk: key,
+        busy: BusyPolicy::Fail,
+        probe: false,
+    };
+
+    Ok(client::run_client(run.target.clone(), options, request.streams, run.block_size_kb, request.duration_secs, request.direction, false).await)
+}
+
+/// Result of a run command, sent by the agent after `ok`:
+/// `result <unix ms> <bytes> <duration secs> <latency ms or -> <errors> <bytes per stream, comma separated or ->`.
+pub fn result_line(measurement: &Measurement) -> String {
+    let streams: Vec<String> = measurement.stream_bytes.iter().map(usize::to_string).collect();
+    format!(
+        "result {} {} {} {} {} {}\n",
+        measurement.timestamp.timestamp_millis(),
+        measurement.bytes,
+        measurement.duration_secs,
+        measurement.latency_ms.map_or("-".to_string(), |latency| latency.to_string()),
+        measurement.errors,
+        if streams.is_empty() { "-".to_string() } else { streams.join(",") }
+    )
+}
+
+/// Parses a `result` line into the measurement of `target`. On error, returns the reason.
+pub fn parse_result(line: &str, target: String, direction: Direction, block_size_kb: usize) -> Result<Measurement, String> {
+    let invalid = || format!("Invalid result '{}'", line);
+    let fields: Vec<&str> = line.split(' ').collect();
+    let ["result", timestamp, bytes, duration, latency, errors, streams] = fields.as_slice() else {
+        return Err(match line.strip_prefix("error ") {
+            Some(reason) => reason.to_string(),
+            None => invalid(),
+        });
+    };
+
+    Ok(Measurement {
+        timestamp: timestamp.parse().ok().and_then(|ms| Local.timestamp_millis_opt(ms).single()).ok_or_else(invalid)?,
+        target,
+        direction,
+        block_size_kb,
+        duration_secs: duration.parse().map_err(|_| invalid())?,
+        bytes: bytes.parse().map_err(|_| invalid())?,
+        latency_ms: match *latency {
+            "-" => None,
+            latency => Some(latency.parse().map_err(|_| invalid())?),
+        },
+        errors: errors.parse().map_err(|_| invalid())?,
+        stream_bytes: match *streams {
+            "-" => Vec::new(),
+            streams => streams.split(',').map(|bytes| bytes.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?,
+        },
+    })
+}
+
+pub struct ControlOptions {
+    /// Agent address, reached with `connect`.
+    pub agent: String,
+    pub connect: ConnectOptions,
+    /// Server the agent tests against, as seen from the agent.
+    pub target: String,
+    pub transport: Transport,
+    pub threads: usize,
+    pub block_size_kb: usize,
+    pub duration_secs: u64,
+    pub direction: Direction,
+}
+
+/// Controller side: has the agent run the test and prints its result. The measurement's target
+/// is `AGENT->TARGET`, so results of several agents stay apart. Returns `None` if the test did not run.
+pub async fn run_control(options: ControlOptions) -> Option<Measurement> {
+    println!(
+        "Asking agent {} to test {:?} against {} with {} streams for {} s",
+        options.agent, options.direction, options.target, options.threads, options.duration_secs
+    );
+    // The agent's own session on its server, limited and queued like the sessions of clients
+    let session = match new_session_id() {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Failed to create a session id: {}", e);
+            return None;
+        }
+    };
+    let request = Request {
+        direction: options.direction,
+        duration_secs: options.duration_secs,
+        session,
+        streams: options.threads,
+        wait: false,
+        run: Some(RunRequest {
+            target: options.target.clone(),
+            block_size_kb: options.block_size_kb,
+            transport: options.transport,
+        }),
+    };
+
+    let line = match command(&options, &request).await {
+        Ok(line) => line,
+        Err(e) => {
+            eprintln!("Agent {}: {}", options.agent, e);
+            return None;
+        }
+    };
+    let label = format!("{}->{}", options.agent, options.target);
+    let measurement = match parse_result(&line, label.clone(), options.direction, options.block_size_kb) {
+        Ok(measurement) => measurement,
+        Err(reason) => {
+            eprintln!("Agent {}: {}", options.agent, reason);
+            return None;
+        }
+    };
+
+    println!("\n[ERGEBNIS] {}", label);
+    println!("Richtung: {:?}", measurement.direction);
+    if let Some(latency_ms) = measurement.latency_ms {
+        println!("Latenz Ø {} ms", format_number(latency_ms, &Locale::de));
+    }
+    if measurement.errors > 0 {
+        println!("Fehlerhafte Streams: {} von {}", measurement.errors, options.threads);
+    }
+    print_statistics_terminal(measurement.duration_secs, measurement.bytes);
+    Some(measurement)
+}
+
+/// Sends the run command and waits for the agent's result line.
+async fn command(options: &ControlOptions, request: &Request) -> io::Result<String> {
+    let connector = NetConnector::resolve(&options.agent, options.connect.clone()).await?;
+    let mut connection = connector.connect(0).await?;
+
+    let text = request.to_lines();
+    let result = match client_handshake(&mut connection.stream, &text, options.connect.psk.as_ref()).await? {
+        Handshake::Accepted => {
+            let wait = Duration::from_secs(options.duration_secs) + RESULT_GRACE;
+            timeout(wait, read_line(&mut connection.stream))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No result from the agent"))?
+        }
+        Handshake::Busy { .. } => Err(io::Error::other("Agent is busy")),
+    };
+
+    let _ = connection.stream.shutdown().await;
+    connector.close().await;
+    match result? {
+        line if line.is_empty() => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Agent closed the connection without a result")),
+        line => Ok(line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::to_json;
+
+    #[test]
+    fn test_result_roundtrip() {
+        let measurement = Measurement {
+            timestamp: Local.timestamp_millis_opt(1_700_000_000_123).unwrap(),
+            target: "a:4000->b:4000".to_string(),
+            latency_ms: Some(0.25),
+            errors: 1,
+            stream_bytes: vec![100_000_000, 25_000_000, 0],
+            ..Measurement::example(Direction::Download, 1000.0)
+        };
+
+        let line = result_line(&measurement);
+        let parsed = parse_result(line.trim(), measurement.target.clone(), Direction::Download, 64).unwrap();
+        assert_eq!(to_json(&parsed), to_json(&measurement));
+
+        let failed = parse_result("error test against b:4000 failed", String::new(), Direction::Upload, 64);
+        assert_eq!(failed.err().as_deref(), Some("test against b:4000 failed"));
+        assert!(parse_result("result 1 2", String::new(), Direction::Upload, 64).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::{Duration, timeout};
+
+use speedtest::{
+    Direction,
+    net::BoxStream,
+    protocol::read_line,
+    sink::{Measurement, json_string, to_json},
+    tls::{self, TlsClientOptions},
+    utils::median,
+};
+
+/// Runs needed before a baseline is trusted.
+const MIN_BASELINE_RUNS: usize = 3;
+
+/// Longest time an alert hook may take.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Thresholds, regression detection and hooks of the monitor. Only network tests are checked.
+#[derive(Clone, Debug, Default)]
+pub struct AlertOptions {
+    pub min_mbit: Option<f64>,
+    pub max_latency_ms: Option<f64>,
+    pub max_errors: Option<usize>,
+    /// Alert when throughput drops this many percent below the median of the recent runs.
+    pub max_drop_percent: Option<f64>,
+    /// Recent runs per target and direction forming the baseline.
+    pub baseline_runs: usize,
+    /// Run via the shell, details in `SPEEDTEST_ALERT*` environment variables.
+    pub command: Option<String>,
+    pub webhook: Option<Webhook>,
+}
+
+/// Checks results against the alert options and keeps the rolling baseline.
+pub struct Alerts {
+    options: AlertOptions,
+    history: BTreeMap<(String, Direction), VecDeque<f64>>,
+}
+
+impl Alerts {
+    pub fn new(options: AlertOptions) -> Self {
+        Alerts { options, history: BTreeMap::new() }
+    }
+
+    /// Violations of `measurement`, empty if all is well. The throughput joins the baseline afterwards.
+    pub fn check(&mut self, measurement: &Measurement) -> Vec<String> {
+        let mut violations = Vec::new();
+        let mbit = measurement.mbit_per_sec();
+
+        if let Some(min) = self.options.min_mbit
+            && mbit < min
+        {
+            violations.push(format!("throughput {:.1} MBit/s below {} MBit/s", mbit, min));
+        }
+        if let (Some(max), Some(latency)) = (self.options.max_latency_ms, measurement.latency_ms)
+            && latency > max
+        {
+            violations.push(format!("latency {:.1} ms above {} ms", latency, max));
+        }
+        if let Some(max) = self.options.max_errors
+            && measurement.errors > max
+        {
+            violations.push(format!("{} failed streams, at most {} allowed", measurement.errors, max));
+        }
+
+        let history = self.history.entry((measurement.target.clone(), measurement.direction)).or_default();
+        if let Some(percent) = self.options.max_drop_percent
+            && history.len() >= MIN_BASELINE_RUNS
+        {
+            let baseline = median(history.make_contiguous());
+            if mbit < baseline * (1.0 - percent / 100.0) {
+                violations.push(format!(
+                    "throughput {:.1} MBit/s more than {}% below the baseline of {:.1} MBit/s over the last {} runs",
+                    mbit,
+                    percent,
+                    baseline,
+                    history.len()
+                ));
+            }
+        }
+
+        // Tests that moved nothing would drag the baseline down instead of standing out
+        if measurement.bytes > 0 {
+            history.push_back(mbit);
+            while history.len() > self.options.baseline_runs.max(1) {
+                history.pop_front();
+            }
+        }
+
+        violations
+    }
+
+    /// Reports `violations` on stderr and to the configured hooks.
+    pub async fn raise(&self, measurement: &Measurement, violations: &[String]) {
+        let summary = format!("{} {:?}: {}", measurement.target, measurement.direction, violations.join("; "));
+        eprintln!("Alert for {}", summary);
+
+        if let Some(command) = &self.options.command
+            && let Err(e) = run_command(command, measurement, violations).await
+        {
+            eprintln!("Alert command failed: {}", e);
+        }
+
+        if let Some(webhook) = &self.options.webhook {
+            let violations = violations.iter().map(|violation| json_string(violation)).collect::<Vec<_>>().join(",");
+            let body = format!(
+                "{{\"text\":{},\"measurement\":{},\"violations\":[{}]}}",
+                json_string(&format!("Speedtest alert for {}", summary)),
+                to_json(measurement),
+                violations
+            );
+            if let Err(e) = webhook.post(&body).await {
+                eprintln!("Alert webhook failed: {}", e);
+            }
+        }
+    }
+}
+
+async fn run_command(command: &str, measurement: &Measurement, violations: &[String]) -> io::Result<()> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("SPEEDTEST_ALERT", violations.join("; "))
+        .env("SPEEDTEST_ALERT_JSON", to_json(measurement))
+        .env("SPEEDTEST_TARGET", &measurement.target)
+        .env("SPEEDTEST_DIRECTION", format!("{:?}", measurement.direction))
+        .env("SPEEDTEST_MBIT", measurement.mbit_per_sec().to_string())
+        .env("SPEEDTEST_LATENCY_MS", measurement.latency_ms.map(|latency| latency.to_string()).unwrap_or_default())
+        .env("SPEEDTEST_ERRORS", measurement.errors.to_string())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let status = timeout(HOOK_TIMEOUT, child.wait())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Alert command timed out"))??;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("Alert command exited with {}", status))),
+    }
+}
+
+/// Webhook receiving alerts as JSON, given as `http://HOST[:PORT]/PATH` or `https://...`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, rest) = match (s.strip_prefix("http://"), s.strip_prefix("https://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => return Err(format!("Invalid webhook '{}', expected http:// or https:// URL", s)),
+        };
+        let (authority, path) = rest.find('/').map_or((rest, "/"), |index| rest.split_at(index));
+        let default_port = if tls { 443 } else { 80 };
+
+        // IPv6 hosts are bracketed, so only a colon after the closing bracket starts the port
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority[index..].contains(']') => {
+                let port = authority[index + 1..].parse().map_err(|_| format!("Invalid port in webhook '{}'", s))?;
+                (&authority[..index], port)
+            }
+            _ => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in webhook '{}'", s));
+        }
+
+        Ok(Webhook {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Webhook {
+    /// Sends `body` as JSON and expects a 2xx status.
+    pub async fn post(&self, body: &str) -> io::Result<()> {
+        timeout(HOOK_TIMEOUT, self.send(body)).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Webhook timed out"))?
+    }
+
+    async fn send(&self, body: &str) -> io::Result<()> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let mut stream: BoxStream = Box::new(TcpStream::connect((host, self.port)).await?);
+        if self.tls {
+            let options = TlsClientOptions {
+                config: tls::web_client_config()?,
+                server_name: None,
+            };
+            stream = tls::connect(stream, &options, &self.host).await?.0;
+        }
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: speedtest/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            env!("CARGO_PKG_VERSION"),
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let status = read_line(&mut stream).await?;
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("Webhook answered '{}'", status))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn measurement(mbit: f64, latency_ms: f64, errors: usize) -> Measurement {
+        Measurement {
+            timestamp: Local::now(),
+            target: "127.0.0.1:4000".to_string(),
+            direction: Direction::Download,
+            block_size_kb: 64,
+            duration_secs: 1.0,
+            bytes: (mbit * 125_000.0) as usize,
+            latency_ms: Some(latency_ms),
+            errors,
+            stream_bytes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_thresholds_and_baseline() {
+        let mut alerts = Alerts::new(AlertOptions {
+            min_mbit: Some(50.0),
+            max_latency_ms: Some(20.0),
+            max_errors: Some(0),
+            max_drop_percent: Some(30.0),
+            baseline_runs: 4,
+            ..AlertOptions::default()
+        });
+
+        assert_eq!(alerts.check(&measurement(40.0, 30.0, 1)).len(), 3);
+        for _ in 0..4 {
+            assert!(alerts.check(&measurement(100.0, 5.0, 0)).is_empty());
+        }
+        // 60 is fine for the threshold but far below the baseline of 100
+        let violations = alerts.check(&measurement(60.0, 5.0, 0));
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("baseline of 100.0"));
+        assert!(alerts.check(&measurement(80.0, 5.0, 0)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        assert_eq!(
+            "https://[::1]:8443/hook".parse::<Webhook>(),
+            Ok(Webhook {
+                tls: true,
+                host: "[::1]".to_string(),
+                port: 8443,
+                path: "/hook".to_string()
+            })
+        );
+        assert!("ftp://example.com".parse::<Webhook>().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook: Webhook = format!("http://{}/alert", listener.local_addr().unwrap()).parse().unwrap();
+        let stub = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        webhook.post("{\"text\":\"slow\"}").await.unwrap();
+        let request = stub.await.unwrap();
+        assert!(request.starts_with("POST /alert HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"text\":\"slow\"}"));
+    }
+}
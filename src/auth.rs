@@ -0,0 +1,108 @@
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Bytes of randomness per challenge.
+const NONCE_LEN: usize = 32;
+
+/// Pre-shared key. `Debug` never prints the secret.
+#[derive(Clone)]
+pub struct Key(Arc<hmac::Key>);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn new(secret: &[u8]) -> Self {
+        Key(Arc::new(hmac::Key::new(hmac::HMAC_SHA256, secret)))
+    }
+
+    /// Key from the command line (or environment) value, or from the first line of `file`.
+    pub fn load(value: Option<String>, file: Option<&Path>) -> io::Result<Option<Key>> {
+        let secret = match (value, file) {
+            (Some(value), _) => value,
+            (None, Some(path)) => std::fs::read_to_string(path)?.lines().next().unwrap_or_default().to_string(),
+            (None, None) => return Ok(None),
+        };
+
+        if secret.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Pre-shared key must not be empty"));
+        }
+
+        Ok(Some(Key::new(secret.as_bytes())))
+    }
+}
+
+/// Keys the server asks for. Administrative commands fall back to the session key if no admin key is set.
+#[derive(Clone, Debug, Default)]
+pub struct ServerAuth {
+    pub session: Option<Key>,
+    pub admin: Option<Key>,
+}
+
+impl ServerAuth {
+    pub fn admin_key(&self) -> Option<&Key> {
+        self.admin.as_ref().or(self.session.as_ref())
+    }
+}
+
+pub fn new_nonce() -> io::Result<String> {
+    random_hex(NONCE_LEN)
+}
+
+pub fn random_hex(len: usize) -> io::Result<String> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| io::Error::other("No randomness available"))?;
+    Ok(to_hex(&bytes))
+}
+
+/// The response binds the nonce to the exact request, so it cannot be replayed for another mode or duration.
+fn message(nonce: &str, request: &str) -> Vec<u8> {
+    [nonce.as_bytes(), b"\n", request.as_bytes()].concat()
+}
+
+pub fn respond(key: &Key, nonce: &str, request: &str) -> String {
+    to_hex(hmac::sign(&key.0, &message(nonce, request)).as_ref())
+}
+
+pub fn verify(key: &Key, nonce: &str, request: &str, response: &str) -> bool {
+    match from_hex(response) {
+        Some(tag) => hmac::verify(&key.0, &message(nonce, request), &tag).is_ok(),
+        None => false,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_response() {
+        let key = Key::new(b"secret");
+        let nonce = new_nonce().unwrap();
+        let response = respond(&key, &nonce, "download\n10\n");
+
+        assert!(verify(&key, &nonce, "download\n10\n", &response));
+        assert!(!verify(&key, &nonce, "download\n99\n", &response), "Response must be bound to the request");
+        assert!(!verify(&Key::new(b"other"), &nonce, "download\n10\n", &response));
+        assert!(!verify(&key, &nonce, "download\n10\n", "zz"));
+    }
+}
@@ -1,119 +1,456 @@
 use crate::{
     Direction,
-    file::{read_test_file, write_test_file},
-    utils::{generate_test_sizes, print_statistics},
-};
-use std::sync::atomic::Ordering;
-use std::sync::{Arc, atomic::AtomicUsize};
-use tokio::net::TcpStream;
-use tokio::time::{Duration, Instant};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    time::sleep,
+    clock::{Clock, TokioClock},
+    engine::{Role, StreamTest, Transfer, run_stream},
+    live::Meters,
+    net::{BusyPolicy, ConnectOptions, Connection, NetConnector, Target},
+    protocol::{Handshake, Request, client_handshake, new_session_id, next_reply},
+    sink::Measurement,
+    tls::TlsInfo,
+    transport::Connector,
+    tui::Tui,
+    utils::{Statistics, format_number, write_statistics_terminal},
 };
+use chrono::Local;
+use num_format::Locale;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, sleep};
 
-pub async fn run_client_loop(address: String, threads: usize, block_size_kb: usize, duration_secs: u64, path: &str, file_size_mb: usize) {
-    // Then do file write and read test
-    let sizes = generate_test_sizes(file_size_mb);
+/// Longest pause between two attempts while the server is busy.
+const MAX_RETRY_DELAY_SECS: u64 = 10;
 
-    loop {
-        // First do HTTP test with download and upload
-        run_client(address.clone(), threads, block_size_kb, duration_secs, Direction::Download).await;
-        sleep(Duration::from_secs(1)).await;
-        run_client(address.clone(), threads, block_size_kb, duration_secs, Direction::Upload).await;
-        sleep(Duration::from_secs(1)).await;
-
-        for size in &sizes {
-            // First write file
-            let duration_res = write_test_file(path, *size).await.unwrap();
-            print_statistics(duration_res.as_secs_f64(), *size, Direction::Upload, 0, path);
-
-            // Now read file
-            let duration_res = read_test_file(path).await.unwrap();
-            print_statistics(duration_res.as_secs_f64(), *size, Direction::Download, 0, path);
+/// Outcome of a test run with [`Client::run`].
+#[derive(Clone, Debug)]
+pub struct TestResult {
+    pub measurement: Measurement,
+    pub statistics: Statistics,
+    /// Version and cipher suite of the first TLS stream, with the mean handshake time of all streams.
+    pub tls: Option<TlsInfo>,
+}
+
+/// Builder for one test against a speedtest server. Defaults are those of the `client` command:
+/// 4 upload streams of 64 KiB blocks for 10 s over plain TCP.
+#[derive(Clone)]
+pub struct Client {
+    address: String,
+    options: ConnectOptions,
+    streams: usize,
+    block_size_kb: usize,
+    duration_secs: u64,
+    direction: Direction,
+    verbose: bool,
+    meters: Arc<Meters>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Client {
+    /// `address` is `HOST:PORT` or `unix:/path`.
+    pub fn new(address: impl Into<String>) -> Client {
+        Client {
+            address: address.into(),
+            options: ConnectOptions::default(),
+            streams: 4,
+            block_size_kb: 64,
+            duration_secs: 10,
+            direction: Direction::Upload,
+            verbose: false,
+            meters: Arc::new(Meters::default()),
+            clock: Arc::new(TokioClock),
         }
     }
-}
 
-pub async fn run_client(address: String, threads: usize, block_size_kb: usize, duration_secs: u64, direction: Direction) {
-    println!("Connecting to {} with {} async tasks in '{:?}' mode", address, threads, direction);
-    let total_bytes = Arc::new(AtomicUsize::new(0));
-    let block_size = block_size_kb * 1024;
-
-    let mut handles = Vec::new();
-    for _ in 0..threads {
-        let addr = address.clone();
-        let bytes = Arc::clone(&total_bytes);
-        let dir = direction;
-
-        let handle = tokio::spawn(async move {
-            let mut stream = TcpStream::connect(&addr).await.expect("Failed to connect");
-            let mode = match direction {
-                Direction::Download => format!("download\n{}\n", duration_secs),
-                Direction::Upload => "upload\n".to_string(),
-                Direction::Bidirectional => format!("bidirectional\n{}\n", duration_secs),
-                Direction::Quit => "quit\n".to_string(),
-            };
-            stream.write_all(mode.as_bytes()).await.expect("Failed to send direction");
-
-            let mut buf = vec![0u8; block_size];
-            let start = Instant::now();
-            let deadline = start + Duration::from_secs(duration_secs);
-
-            //println!("start / end: {}", format_duration_hms(start, deadline));
-
-            let mut count = 0;
-
-            match dir {
-                Direction::Upload => {
-                    while Instant::now() < deadline {
-                        if stream.write_all(&buf).await.is_err() {
-                            break;
-                        }
-                        count += buf.len();
-                    }
-                }
-                Direction::Download => {
-                    while Instant::now() < deadline {
-                        match stream.read(&mut buf).await {
-                            Ok(0) => break,
-                            Ok(n) => count += n,
-                            Err(_) => break,
-                        }
-                    }
-                }
-                Direction::Bidirectional => {
-                    // Optional: alternate sending and receiving in bidirectional mode
-                    while Instant::now() < deadline {
-                        if stream.write_all(&buf).await.is_err() {
-                            break;
-                        }
-                        count += buf.len();
-                        if let Ok(n) = stream.read(&mut buf).await {
-                            if n == 0 {
-                                break;
-                            }
-                            count += n;
+    /// Address family, TLS or QUIC, key and busy policy.
+    pub fn connect_options(mut self, options: ConnectOptions) -> Client {
+        self.options = options;
+        self
+    }
+
+    pub fn streams(mut self, streams: usize) -> Client {
+        self.streams = streams;
+        self
+    }
+
+    pub fn block_size_kb(mut self, block_size_kb: usize) -> Client {
+        self.block_size_kb = block_size_kb;
+        self
+    }
+
+    pub fn duration_secs(mut self, duration_secs: u64) -> Client {
+        self.duration_secs = duration_secs;
+        self
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Client {
+        self.direction = direction;
+        self
+    }
+
+    /// Print the progress of the streams (connects, TLS handshakes, queue positions) like the `client` command.
+    pub fn verbose(mut self, verbose: bool) -> Client {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Time source of the stream deadlines, tokio's clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Client {
+        self.clock = clock;
+        self
+    }
+
+    /// Live byte counters of the running streams, e.g. for a progress display.
+    pub fn meters(&self) -> Arc<Meters> {
+        Arc::clone(&self.meters)
+    }
+
+    /// Runs the test over TCP, a Unix socket, TLS or QUIC as the address and connect options say.
+    /// Fails if the server cannot be reached or does not accept the test, streams that break off
+    /// later are counted in `errors` of the measurement.
+    pub async fn run(&self) -> io::Result<TestResult> {
+        let connector = NetConnector::resolve(&self.address, self.options.clone()).await?;
+        if self.verbose
+            && let Target::Quic(connection) = &connector.target
+        {
+            println!(
+                "QUIC connection via {}, handshake in {} ms",
+                connection.description,
+                format_number(connection.handshake.as_secs_f64() * 1000.0, &Locale::de)
+            );
+        }
+        self.run_with(Arc::new(connector)).await
+    }
+
+    /// Runs the test over the streams of `connector`, e.g. an in-memory [`crate::transport::memory`].
+    /// The connect options only add the key and busy policy.
+    pub async fn run_with(&self, connector: Arc<dyn Connector>) -> io::Result<TestResult> {
+        let Client {
+            address,
+            options,
+            streams: threads,
+            block_size_kb,
+            duration_secs,
+            direction,
+            verbose,
+            meters,
+            clock,
+        } = self.clone();
+        let block_size = block_size_kb * 1024;
+        let options = Arc::new(options);
+        let request = Arc::new(Request {
+            direction,
+            duration_secs,
+            session: new_session_id()?,
+            streams: threads,
+            wait: options.busy == BusyPolicy::Wait,
+            run: None,
+        });
+
+        // The first stream waits for the server, the others join once the session runs
+        let mut first = match open_stream(connector.as_ref(), &options, &request, 1, verbose).await {
+            Ok(first) => Some(first),
+            Err(e) => {
+                connector.close().await;
+                return Err(e);
+            }
+        };
+
+        let mut handles = Vec::new();
+        for stream_id in 1..=threads {
+            let opened = first.take();
+            let connector = Arc::clone(&connector);
+            let options = Arc::clone(&options);
+            let request = Arc::clone(&request);
+            let meters = Arc::clone(&meters);
+            let clock = Arc::clone(&clock);
+
+            let handle = tokio::spawn(async move {
+                let opened = match opened {
+                    Some(opened) => Ok(opened),
+                    None => open_stream(connector.as_ref(), &options, &request, stream_id, verbose).await,
+                };
+                let Connection { mut stream, tls, latency, probe, .. } = match opened {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        if verbose {
+                            eprintln!("{}", e);
                         }
+                        return StreamOutcome::default();
                     }
+                };
+                let meter = meters.register(format!("Stream {}", stream_id), probe);
+
+                let start = clock.now();
+                let test = StreamTest {
+                    role: Role::Client,
+                    direction,
+                    block_size,
+                    deadline: start + Duration::from_secs(duration_secs),
+                    limiter: None,
+                    clock: clock.as_ref(),
+                };
+                let Transfer { bytes, ok } = run_stream(&mut stream, &test, &meter).await;
+
+                StreamOutcome {
+                    tls,
+                    latency: Some(latency),
+                    bytes,
+                    ok,
+                    duration: clock.now() - start,
                 }
-                Direction::Quit => { /* Do nothing, is for server only */ }
-            }
+            });
 
-            bytes.fetch_add(count, Ordering::Relaxed);
+            handles.push(handle);
+        }
+
+        let mut outcomes = Vec::new();
+        for h in handles {
+            outcomes.push(h.await.unwrap());
+        }
+        connector.close().await;
+        let tls_sessions: Vec<&TlsInfo> = outcomes.iter().filter_map(|outcome| outcome.tls.as_ref()).collect();
+        let tls = tls_sessions.first().map(|first| TlsInfo {
+            handshake: tls_sessions.iter().map(|tls| tls.handshake).sum::<Duration>() / tls_sessions.len() as u32,
+            ..(*first).clone()
         });
+        let latencies: Vec<f64> = outcomes.iter().filter_map(|outcome| outcome.latency).map(|latency| latency.as_secs_f64() * 1000.0).collect();
+        let latency_ms = (!latencies.is_empty()).then(|| latencies.iter().sum::<f64>() / latencies.len() as f64);
 
-        handles.push(handle);
+        let measurement = Measurement {
+            timestamp: Local::now(),
+            target: address,
+            direction,
+            block_size_kb,
+            duration_secs: received_secs(direction, duration_secs, &outcomes),
+            bytes: outcomes.iter().map(|outcome| outcome.bytes).sum(),
+            latency_ms,
+            errors: outcomes.iter().filter(|outcome| !outcome.ok).count(),
+            stream_bytes: outcomes.iter().map(|outcome| outcome.bytes).collect(),
+        };
+        Ok(TestResult {
+            statistics: measurement.statistics(),
+            measurement,
+            tls,
+        })
     }
+}
 
-    for h in handles {
-        h.await.unwrap();
-    }
+/// Runs one test and prints its result. Returns `None` if the server did not accept the test.
+/// With `tui` the streams are shown live until the result is in.
+pub async fn run_client(address: String, options: ConnectOptions, threads: usize, block_size_kb: usize, duration_secs: u64, direction: Direction, tui: bool) -> Option<Measurement> {
+    let client = Client::new(address.clone())
+        .connect_options(ConnectOptions { probe: tui, ..options })
+        .streams(threads)
+        .block_size_kb(block_size_kb)
+        .duration_secs(duration_secs)
+        .direction(direction)
+        .verbose(true);
+    let tui = tui
+        .then(|| {
+            let title = format!("{} {:?}, {} Streams", address, direction, threads);
+            Tui::start(client.meters(), title, Some(Duration::from_secs(duration_secs)), Box::new(String::new))
+        })
+        .flatten();
 
-    let duration = duration_secs as f64;
-    let total = total_bytes.load(Ordering::Relaxed);
+    println!("Connecting to {} with {} async tasks in '{:?}' mode", address, threads, direction);
+    let result = client.run().await;
+    if let Some(tui) = tui {
+        tui.stop();
+    }
+    let TestResult { measurement, statistics, tls } = match result {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
 
     println!("\n[ERGEBNIS]");
     println!("Richtung: {:?}", direction);
-    print_statistics(duration, total, direction, block_size_kb, &address);
+    if let Some(tls) = tls {
+        println!(
+            "TLS: {} {}, Handshake Ø {} ms",
+            tls.version,
+            tls.cipher_suite,
+            format_number(tls.handshake.as_secs_f64() * 1000.0, &Locale::de)
+        );
+    }
+    if let Some(latency_ms) = measurement.latency_ms {
+        println!("Latenz Ø {} ms", format_number(latency_ms, &Locale::de));
+    }
+    if measurement.errors > 0 {
+        println!("Fehlerhafte Streams: {} von {}", measurement.errors, threads);
+    }
+    write_statistics_terminal(&statistics);
+    Some(measurement)
+}
+
+/// Reads server addresses from `path`, one per line. Empty lines and `#` comments are skipped.
+pub fn read_targets(path: &Path) -> io::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Runs `test` against every target, one after another or all at once. The results keep the order of `targets`.
+pub async fn run_targets<F, T>(targets: &[String], concurrent: bool, test: F) -> Vec<Option<Measurement>>
+where
+    F: Fn(String) -> T,
+    T: Future<Output = Option<Measurement>> + Send + 'static,
+{
+    let mut measurements = Vec::new();
+    if concurrent {
+        let handles: Vec<_> = targets.iter().map(|target| tokio::spawn(test(target.clone()))).collect();
+        for handle in handles {
+            measurements.push(handle.await.unwrap());
+        }
+    } else {
+        for target in targets {
+            measurements.push(test(target.clone()).await);
+        }
+    }
+    measurements
+}
+
+/// Prints one line per target. Concurrent runs shared the client's link, so their sum is shown too.
+pub fn print_summary(targets: &[String], measurements: &[Option<Measurement>], concurrent: bool) {
+    let locale = Locale::de;
+
+    println!("\n[ERGEBNIS] {} Ziele", targets.len());
+    println!("{:<32}  {:<13}  {:>12}  {:>10}  {:>6}", "Adresse", "Richtung", "MBit/s", "Latenz ms", "Fehler");
+    for (target, measurement) in targets.iter().zip(measurements) {
+        match measurement {
+            Some(measurement) => println!(
+                "{:<32}  {:<13}  {:>12}  {:>10}  {:>6}",
+                target,
+                format!("{:?}", measurement.direction),
+                format_number(measurement.mbit_per_sec(), &locale),
+                measurement.latency_ms.map_or("-".to_string(), |latency| format_number(latency, &locale)),
+                measurement.errors
+            ),
+            None => println!("{:<32}  {:<13}  {:>12}  {:>10}  {:>6}", target, "-", "-", "-", "alle"),
+        }
+    }
+
+    if concurrent {
+        let total: f64 = measurements.iter().flatten().map(Measurement::mbit_per_sec).sum();
+        println!("{:<32}  {:<13}  {:>12}", "Gesamt", "", format_number(total, &locale));
+    }
+    let failed = measurements.iter().filter(|measurement| measurement.is_none()).count();
+    if failed > 0 {
+        println!("{} von {} Zielen nicht erreicht", failed, targets.len());
+    }
+}
+
+/// What one stream reports back. A stream that could not be opened or broke off counts as failed.
+#[derive(Default)]
+struct StreamOutcome {
+    tls: Option<TlsInfo>,
+    latency: Option<Duration>,
+    bytes: usize,
+    ok: bool,
+    /// Until the stream was done, for downloads when the last data arrived.
+    duration: Duration,
+}
+
+/// The duration a test's bytes are divided by. What is still on the way at the deadline (e.g. in
+/// the queue of a shaping relay) arrives later, so a test that receives lasts until its last stream
+/// is done, but at least as long as the server sent.
+fn received_secs(direction: Direction, duration_secs: u64, outcomes: &[StreamOutcome]) -> f64 {
+    let nominal = Duration::from_secs(duration_secs);
+    let duration = match direction {
+        Direction::Upload => nominal,
+        _ => outcomes.iter().map(|outcome| outcome.duration).max().unwrap_or_default().max(nominal),
+    };
+    duration.as_secs_f64()
+}
+
+/// Connects stream `stream_id` and runs the handshake. While the server is busy, waits or tries again as `options.busy` allows.
+async fn open_stream(connector: &dyn Connector, options: &ConnectOptions, request: &Request, stream_id: usize, verbose: bool) -> io::Result<Connection> {
+    let mut attempts = 0;
+
+    loop {
+        let mut connection = connector
+            .connect(stream_id - 1)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("Stream {}: Failed to connect: {}", stream_id, e)))?;
+        if verbose {
+            println!("Stream {} connected via {}", stream_id, connection.description);
+        }
+        if verbose && let Some(tls) = &connection.tls {
+            println!(
+                "Stream {} TLS handshake in {} ms ({}, {})",
+                stream_id,
+                format_number(tls.handshake.as_secs_f64() * 1000.0, &Locale::de),
+                tls.version,
+                tls.cipher_suite
+            );
+        }
+
+        let stream = &mut connection.stream;
+        let text = request.to_lines();
+        let mut handshake = client_handshake(stream, &text, options.psk.as_ref()).await;
+
+        // Waiting clients stay connected and get position updates until it is their turn
+        while request.wait
+            && let Ok(Handshake::Busy { position, wait_secs }) = handshake
+        {
+            if verbose {
+                println!("Stream {}: Server busy, waiting at position {} in the queue, estimated wait {} s", stream_id, position, wait_secs);
+            }
+            handshake = next_reply(stream, &text, options.psk.as_ref()).await;
+        }
+
+        match handshake {
+            Ok(Handshake::Accepted) => return Ok(connection),
+            Ok(Handshake::Busy { position, wait_secs }) => {
+                if verbose {
+                    println!("Stream {}: Server busy, position {} in the queue, estimated wait {} s", stream_id, position, wait_secs);
+                }
+                attempts += 1;
+                if !matches!(options.busy, BusyPolicy::Retry(retries) if attempts <= retries) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        format!("Stream {}: Server busy, giving up (see --wait and --retries)", stream_id),
+                    ));
+                }
+
+                let _ = stream.shutdown().await;
+                sleep(Duration::from_secs(wait_secs.clamp(1, MAX_RETRY_DELAY_SECS))).await;
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("Stream {}: {}", stream_id, e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_targets() {
+        let path = std::env::temp_dir().join(format!("speedtest-targets-{}.txt", std::process::id()));
+        std::fs::write(&path, "# sites\nsite-a:4000\n\n  site-b:4000  # backup\nunix:/tmp/c.sock\n").unwrap();
+        let targets = read_targets(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(targets, ["site-a:4000", "site-b:4000", "unix:/tmp/c.sock"]);
+
+        // The first target finishes last, the results still come back in order
+        let measurements = run_targets(&targets, true, |target| async move {
+            let delay = if target.starts_with("site-a") { 50 } else { 0 };
+            sleep(Duration::from_millis(delay)).await;
+            (!target.starts_with("unix")).then(|| Measurement {
+                target,
+                ..Measurement::example(Direction::Download, 100.0)
+            })
+        })
+        .await;
+        let order: Vec<_> = measurements.iter().map(|measurement| measurement.as_ref().map(|m| m.target.as_str())).collect();
+        assert_eq!(order, [Some("site-a:4000"), Some("site-b:4000"), None]);
+    }
 }
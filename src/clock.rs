@@ -0,0 +1,119 @@
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+use crate::transport::BoxFuture;
+
+/// Time source of the test engine: stream deadlines and measured stream durations.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once [`Clock::now`] reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'_, ()>;
+}
+
+/// Tokio's clock, which tests can pause and advance with `tokio::time::pause` or `start_paused`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'_, ()> {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// Clock that only moves when told to, for tests. Paused tokio time only moves while all tasks
+/// wait, which a stream that sends as fast as it can never does; a stepping clock moves on with
+/// every reading instead, so such a loop runs a fixed number of times.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+    step: Duration,
+    advanced: Notify,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::stepping(Duration::ZERO)
+    }
+
+    /// Every call of [`Clock::now`] returns the current time and then advances it by `step`.
+    pub fn stepping(step: Duration) -> ManualClock {
+        ManualClock {
+            now: Mutex::new(Instant::now()),
+            step,
+            advanced: Notify::new(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+        self.advanced.notify_waiters();
+    }
+
+    /// The current time without stepping.
+    pub fn peek(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        let now = self.peek();
+        if !self.step.is_zero() {
+            self.advance(self.step);
+        }
+        now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            loop {
+                // Registered before the check, so an advance in between is not missed
+                let advanced = self.advanced.notified();
+                tokio::pin!(advanced);
+                advanced.as_mut().enable();
+                if self.peek() >= deadline {
+                    return;
+                }
+                advanced.await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let sleeping = clock.sleep_until(start + Duration::from_secs(10));
+        tokio::pin!(sleeping);
+        assert!(timeout(Duration::ZERO, sleeping.as_mut()).await.is_err());
+        clock.advance(Duration::from_secs(9));
+        assert!(timeout(Duration::ZERO, sleeping.as_mut()).await.is_err());
+        clock.advance(Duration::from_secs(1));
+        sleeping.await;
+
+        let stepping = ManualClock::stepping(Duration::from_millis(10));
+        let start = stepping.now();
+        assert_eq!(stepping.now() - start, Duration::from_millis(10));
+        assert_eq!(stepping.peek() - start, Duration::from_millis(20));
+    }
+}
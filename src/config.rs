@@ -0,0 +1,372 @@
+use clap::{ArgMatches, Command, FromArgMatches};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Environment variable naming the config file, like `--config`.
+const CONFIG_ENV: &str = "SPEEDTEST_CONFIG";
+
+/// Picks the subcommand to run when the command line names none.
+const COMMAND_KEY: &str = "command";
+
+/// Settings from a TOML config file. Keys are the long flag names of the commands:
+///
+/// ```toml
+/// [defaults]
+/// address = "speedtest.example.com:4000"
+/// threads = 8
+///
+/// [profiles.wan-quick]
+/// command = "client"
+/// duration-secs = 5
+/// sink = ["jsonl:wan.jsonl"]
+/// ```
+///
+/// A profile is layered over `[defaults]`, flags given on the command line override both.
+pub struct Config {
+    pub path: PathBuf,
+    defaults: Table,
+    profiles: Table,
+}
+
+impl Config {
+    pub fn parse(path: PathBuf, text: &str) -> Result<Config, String> {
+        let mut table: Table = text.parse().map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        let mut section = |name: &str| match table.remove(name) {
+            Some(Value::Table(table)) => Ok(table),
+            Some(_) => Err(format!("Invalid config {}: '{}' must be a table", path.display(), name)),
+            None => Ok(Table::new()),
+        };
+        let (defaults, profiles) = (section("defaults")?, section("profiles")?);
+
+        if let Some(key) = table.keys().next() {
+            return Err(format!("Invalid config {}: unknown section '{}', expected [defaults] or [profiles.NAME]", path.display(), key));
+        }
+        if let Some((name, _)) = profiles.iter().find(|(_, profile)| !profile.is_table()) {
+            return Err(format!("Invalid config {}: profile '{}' must be a table", path.display(), name));
+        }
+
+        Ok(Config { path, defaults, profiles })
+    }
+
+    /// The first config file found: `explicit`, then `SPEEDTEST_CONFIG`, then the search path.
+    /// A file named explicitly must exist, the search path may come up empty.
+    pub fn load(explicit: Option<PathBuf>) -> Result<Option<Config>, String> {
+        let explicit = explicit.or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+        let path = match explicit {
+            Some(path) => path,
+            None => match search_path().into_iter().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(None),
+            },
+        };
+
+        let text = fs::read_to_string(&path).map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        Config::parse(path, &text).map(Some)
+    }
+
+    /// The defaults with `profile` layered on top.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Table, String> {
+        let mut settings = self.defaults.clone();
+        if let Some(name) = profile {
+            match self.profiles.get(name).and_then(Value::as_table) {
+                Some(profile) => settings.extend(profile.clone()),
+                None => {
+                    let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                    return Err(format!("Unknown profile '{}' in {}, known: {}", name, self.path.display(), known.join(", ")));
+                }
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// Where a config file is looked for without `--config`, in order.
+pub fn search_path() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("speedtest.toml")];
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("speedtest").join("config.toml"));
+    }
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/speedtest.toml"));
+    }
+    paths
+}
+
+/// Parses the command line with the config file's values as defaults. Exits on errors like clap does.
+pub fn parse_args<T: FromArgMatches + clap::CommandFactory>() -> T {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let (path, profile) = find_options(&args);
+
+    let settings = Config::load(path).and_then(|config| match (config, &profile) {
+        (Some(config), profile) => config.settings(profile.as_deref()),
+        (None, Some(profile)) => Err(format!("Profile '{}' given, but no config file found (see --config)", profile)),
+        (None, None) => Ok(Table::new()),
+    });
+    let matches = settings.and_then(|settings| matches(T::command(), args, &settings)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    T::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+/// Values of `--config` and `--profile`, which are needed before the real parse.
+fn find_options(args: &[OsString]) -> (Option<PathBuf>, Option<String>) {
+    let (mut config, mut profile) = (None, None);
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg.to_string(), None),
+        };
+        match name.as_str() {
+            "--" => break,
+            "--config" => config = value.or_else(|| args.next().map(|value| value.to_string())).map(PathBuf::from),
+            "--profile" => profile = value.or_else(|| args.next().map(|value| value.to_string())),
+            _ => {}
+        }
+    }
+
+    (config, profile)
+}
+
+fn matches(command: Command, mut args: Vec<OsString>, settings: &Table) -> Result<ArgMatches, String> {
+    let command = apply(command, &mut args, settings)?;
+    Ok(command.get_matches_from(args))
+}
+
+/// What a bare `speedtest` runs without a `command` setting: the monitor against a local server.
+const DEFAULT_COMMAND: &str = "monitor";
+const DEFAULT_SETTINGS: [(&str, &str); 3] = [("address", "127.0.0.1:4000"), ("block-size-kb", "100"), ("file-size-mb", "10")];
+
+/// Turns `settings` into defaults of the subcommand that runs, inserting the `command` setting
+/// (or the monitor) into `args` if they name no subcommand.
+fn apply(mut command: Command, args: &mut Vec<OsString>, settings: &Table) -> Result<Command, String> {
+    let mut settings = settings.clone();
+    let name = match (find_subcommand(&command, args), settings.remove(COMMAND_KEY)) {
+        (Some(Position::Named(name)), _) => name,
+        (Some(Position::Help), _) => return Ok(command),
+        (None, Some(Value::String(name))) if command.find_subcommand(&name).is_some() => {
+            args.insert(1.min(args.len()), OsString::from(&name));
+            name
+        }
+        (None, Some(_)) => return Err(format!("Invalid setting '{}', expected the name of a command", COMMAND_KEY)),
+        (None, None) => {
+            args.insert(1.min(args.len()), OsString::from(DEFAULT_COMMAND));
+            for (key, value) in DEFAULT_SETTINGS {
+                settings.entry(key).or_insert_with(|| Value::String(value.to_string()));
+            }
+            DEFAULT_COMMAND.to_string()
+        }
+    };
+    let Some(mut subcommand) = command.find_subcommand(&name).cloned() else {
+        return Ok(command);
+    };
+
+    for (key, value) in &settings {
+        let long = key.replace('_', "-");
+        if !has_flag(&command, &long) {
+            return Err(format!("Unknown setting '{}', expected the long name of a flag", key));
+        }
+        if !has_flag(&subcommand, &long) {
+            eprintln!("Warning: setting '{}' does not apply to '{}', ignoring it", key, name);
+            continue;
+        }
+        match value {
+            // A flag's default is off, `--no-FLAG` turns off one the config file turns on
+            Value::Boolean(false) => {}
+            Value::Boolean(true) => {
+                let negated = format!("--no-{}", long);
+                subcommand = add_negation(subcommand, &long);
+                if !args.iter().take_while(|arg| *arg != "--").any(|arg| *arg == *negated) {
+                    subcommand = set_default(subcommand, &long, &["true".to_string()]);
+                }
+            }
+            value => subcommand = set_default(subcommand, &long, &to_strings(key, value)?),
+        }
+    }
+
+    command = command.mut_subcommand(&name, |_| subcommand);
+    Ok(command)
+}
+
+/// The subcommand named in `args`, or a request for help.
+enum Position {
+    Named(String),
+    Help,
+}
+
+/// Finds the subcommand in `args`, skipping the values of the options before it (e.g. `--profile client`).
+/// Anything else in front of the subcommand belongs to the one that is inserted.
+fn find_subcommand(command: &Command, args: &[OsString]) -> Option<Position> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            return None;
+        }
+        let takes_value = if let Some(long) = arg.strip_prefix("--") {
+            if matches!(long, "help" | "version") {
+                return Some(Position::Help);
+            }
+            !long.contains('=') && command.get_arguments().any(|a| a.get_long() == Some(long) && a.get_action().takes_values())
+        } else if let Some(short) = arg.strip_prefix('-').filter(|short| !short.is_empty()) {
+            if short.starts_with(['h', 'V']) {
+                return Some(Position::Help);
+            }
+            short.chars().count() == 1 && command.get_arguments().any(|a| a.get_short() == short.chars().next() && a.get_action().takes_values())
+        } else if arg == "help" {
+            return Some(Position::Help);
+        } else if command.find_subcommand(&*arg).is_some() {
+            return Some(Position::Named(arg.to_string()));
+        } else {
+            return None;
+        };
+        if takes_value {
+            args.next();
+        }
+    }
+    None
+}
+
+/// Adds `--no-FLAG` wherever `command` or its subcommands have `--FLAG`.
+fn add_negation(mut command: Command, long: &str) -> Command {
+    let ids: Vec<_> = command.get_arguments().filter(|arg| arg.get_long() == Some(long)).map(|arg| arg.get_id().to_string()).collect();
+    for id in ids {
+        let negation = format!("no-{}", long);
+        if command.get_arguments().all(|arg| arg.get_long() != Some(negation.as_str())) {
+            command = command.arg(
+                clap::Arg::new(format!("no_{}", id))
+                    .long(negation)
+                    .action(clap::ArgAction::SetTrue)
+                    .conflicts_with(id)
+                    .help(format!("Turn off --{}, which the config file turns on", long)),
+            );
+        }
+    }
+
+    let names: Vec<_> = command.get_subcommands().map(|subcommand| subcommand.get_name().to_string()).collect();
+    for name in names {
+        command = command.mut_subcommand(name, |subcommand| add_negation(subcommand, long));
+    }
+    command
+}
+
+/// Whether `command` or any of its subcommands has the flag `--long`.
+fn has_flag(command: &Command, long: &str) -> bool {
+    command.get_arguments().any(|arg| arg.get_long() == Some(long)) || command.get_subcommands().any(|subcommand| has_flag(subcommand, long))
+}
+
+/// Settings apply to nested subcommands too (e.g. the filters of `history list`).
+fn set_default(mut command: Command, long: &str, values: &[String]) -> Command {
+    let ids: Vec<_> = command.get_arguments().filter(|arg| arg.get_long() == Some(long)).map(|arg| arg.get_id().clone()).collect();
+    for id in ids {
+        // A default satisfies the argument, whatever else it is required with
+        command = command.mut_arg(id, |arg| arg.default_values(values.to_vec()).required(false).required_unless_present(clap::builder::Resettable::Reset));
+    }
+
+    let names: Vec<_> = command.get_subcommands().map(|subcommand| subcommand.get_name().to_string()).collect();
+    for name in names {
+        command = command.mut_subcommand(name, |subcommand| set_default(subcommand, long, values));
+    }
+    command
+}
+
+/// Arrays set flags that can be repeated, like `sink`.
+fn to_strings(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(values) => values.iter().map(|value| to_string(key, value)).collect(),
+        value => to_string(key, value).map(|value| vec![value]),
+    }
+}
+
+fn to_string(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Datetime(datetime) => Ok(datetime.to_string()),
+        Value::Array(_) | Value::Table(_) => Err(format!("Invalid setting '{}': expected a value or a list of values", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_profiles() {
+        let config = Config::parse(
+            PathBuf::from("speedtest.toml"),
+            r#"
+                [defaults]
+                address = "10.0.0.1:4000"
+                threads = 8
+
+                [profiles.wan-quick]
+                command = "client"
+                duration-secs = 5
+                sink = ["jsonl:wan.jsonl", "csv:wan.csv"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.settings(Some("lan-full")).unwrap_err().contains("known: wan-quick"));
+
+        let settings = config.settings(Some("wan-quick")).unwrap();
+        let mut args = vec![OsString::from("speedtest"), OsString::from("-t"), OsString::from("2")];
+        let command = apply(crate::Args::command(), &mut args, &settings).unwrap();
+        assert_eq!(args[1], "client");
+
+        let matches = command.try_get_matches_from(args).unwrap();
+        let client = matches.subcommand_matches("client").unwrap();
+        assert_eq!(client.get_one::<String>("address").unwrap(), "10.0.0.1:4000");
+        // The command line wins over the config file
+        assert_eq!(client.get_one::<usize>("threads"), Some(&2));
+        assert_eq!(client.get_one::<u64>("duration_secs"), Some(&5));
+        assert_eq!(client.get_many::<crate::Sink>("sink").unwrap().count(), 2);
+
+        let mut typo = Table::new();
+        typo.insert("thread".to_string(), Value::Integer(4));
+        assert!(apply(crate::Args::command(), &mut vec![OsString::from("speedtest"), OsString::from("client")], &typo).is_err());
+    }
+
+    #[test]
+    fn test_flags_and_default_command() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let mut settings = Table::new();
+        settings.insert("wait".to_string(), Value::Boolean(true));
+        settings.insert("max-sessions".to_string(), Value::Integer(2));
+
+        // A flag turned on by the config file can be turned off again
+        for (given, wait) in [
+            (args(&["speedtest", "client", "-a", "host:4000"]), true),
+            (args(&["speedtest", "client", "-a", "host:4000", "--no-wait"]), false),
+        ] {
+            let mut given = given;
+            let command = apply(crate::Args::command(), &mut given, &settings).unwrap();
+            let matches = command.try_get_matches_from(given).unwrap();
+            assert_eq!(matches.subcommand_matches("client").unwrap().get_flag("wait"), wait);
+        }
+
+        // The value of --profile is not a subcommand, a bare call runs the monitor
+        let mut given = args(&["speedtest", "--profile", "client"]);
+        let command = apply(crate::Args::command(), &mut given, &Table::new()).unwrap();
+        assert_eq!(given[1], "monitor");
+        let matches = command.try_get_matches_from(given).unwrap();
+        let monitor = matches.subcommand_matches("monitor").unwrap();
+        assert_eq!(monitor.get_one::<String>("address").unwrap(), "127.0.0.1:4000");
+        assert_eq!(monitor.get_one::<usize>("block_size_kb"), Some(&100));
+
+        let mut given = args(&["speedtest", "--help"]);
+        apply(crate::Args::command(), &mut given, &Table::new()).unwrap();
+        assert_eq!(given.len(), 2);
+    }
+}
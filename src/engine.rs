@@ -0,0 +1,235 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
+
+use crate::{Direction, clock::Clock, limits::RateLimiter, live::Meter, net::BoxStream};
+
+/// Time a receiver waits past the deadline for the sender to finish, e.g. to drain socket buffers.
+const RECEIVE_GRACE: Duration = Duration::from_secs(2);
+
+/// The end of a stream a test runs on. The client sends uploads, the server downloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// What one stream of a test transferred. A stream that broke off is not `ok`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transfer {
+    pub bytes: usize,
+    pub ok: bool,
+}
+
+/// One side of one stream of a test.
+pub struct StreamTest<'a> {
+    pub role: Role,
+    pub direction: Direction,
+    pub block_size: usize,
+    /// When the sending side stops, from `clock`.
+    pub deadline: Instant,
+    pub limiter: Option<&'a RateLimiter>,
+    pub clock: &'a dyn Clock,
+}
+
+/// Runs one stream of a test until its deadline, then shuts the stream down.
+/// Client and server run the same loops over any transport, `role` picks the side.
+pub async fn run_stream(stream: &mut BoxStream, test: &StreamTest<'_>, meter: &Meter) -> Transfer {
+    let deadline = test.deadline;
+    let transfer = match (test.direction, test.role) {
+        (Direction::Upload, Role::Client) | (Direction::Download, Role::Server) => send(stream, test, meter).await,
+        // The server stops first, as its deadline starts before the client's, so the client reads
+        // everything still on the way instead of stopping at its own deadline
+        (Direction::Download, Role::Client) => receive(stream, test, deadline + RECEIVE_GRACE, None, meter).await,
+        // The client stops on its own, the grace period only ends uploads that run over.
+        // Ending before the announced duration means the client aborted.
+        (Direction::Upload, Role::Server) => receive(stream, test, deadline + RECEIVE_GRACE, Some(deadline), meter).await,
+        (Direction::Bidirectional, _) => exchange(stream, test, meter).await,
+        (Direction::Quit, _) => Transfer { bytes: 0, ok: true },
+    };
+
+    // Closes TLS sessions cleanly (close_notify) before the FIN
+    let _ = stream.shutdown().await;
+    transfer
+}
+
+/// Sends blocks until the deadline.
+async fn send(stream: &mut BoxStream, test: &StreamTest<'_>, meter: &Meter) -> Transfer {
+    let buf = vec![0u8; test.block_size];
+    let mut transfer = Transfer { bytes: 0, ok: true };
+    while test.clock.now() < test.deadline {
+        if let Some(limiter) = test.limiter {
+            limiter.acquire(buf.len()).await;
+        }
+        if stream.write_all(&buf).await.is_err() {
+            transfer.ok = false;
+            break;
+        }
+        transfer.bytes += buf.len();
+        meter.add(buf.len());
+    }
+    transfer
+}
+
+/// Receives until the peer closes or `until` passes. An end of stream before `expected_end` counts as failed.
+async fn receive(stream: &mut (impl AsyncRead + Unpin), test: &StreamTest<'_>, until: Instant, expected_end: Option<Instant>, meter: &Meter) -> Transfer {
+    let mut buf = vec![0u8; test.block_size];
+    let mut transfer = Transfer { bytes: 0, ok: true };
+    let mut timeout = test.clock.sleep_until(until);
+    loop {
+        let read = tokio::select! {
+            read = stream.read(&mut buf) => read,
+            _ = &mut timeout => break,
+        };
+        match read {
+            Ok(0) => {
+                transfer.ok = expected_end.is_none_or(|end| test.clock.now() >= end);
+                break;
+            }
+            Ok(n) => {
+                transfer.bytes += n;
+                meter.add(n);
+                if let Some(limiter) = test.limiter {
+                    limiter.acquire(n).await;
+                }
+            }
+            Err(_) => {
+                transfer.ok = false;
+                break;
+            }
+        }
+    }
+    transfer
+}
+
+/// Bidirectional transfer: sends until `deadline` while receiving until the peer closes its side.
+/// Alternating write and read on one task deadlocks as soon as both peers fill each other's
+/// receive window (QUIC flow control, small socket buffers), so both halves run concurrently.
+/// With a `limiter`, both directions together stay within its rate. Both directions count into `meter`.
+/// Neither half waits for a stalled peer past the grace period. The server's side fails if the
+/// client closes before the deadline, like an upload.
+async fn exchange(stream: &mut BoxStream, test: &StreamTest<'_>, meter: &Meter) -> Transfer {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let until = test.deadline + RECEIVE_GRACE;
+
+    let send = async move {
+        let buf = vec![0u8; test.block_size];
+        let mut transfer = Transfer { bytes: 0, ok: true };
+        let mut timeout = test.clock.sleep_until(until);
+        while test.clock.now() < test.deadline {
+            if let Some(limiter) = test.limiter {
+                limiter.acquire(buf.len()).await;
+            }
+            let written = tokio::select! {
+                written = writer.write_all(&buf) => written,
+                _ = &mut timeout => break,
+            };
+            if written.is_err() {
+                transfer.ok = false;
+                break;
+            }
+            transfer.bytes += buf.len();
+            meter.add(buf.len());
+        }
+        // Lets the peer's receiving half see EOF while this side keeps reading
+        let _ = writer.shutdown().await;
+        transfer
+    };
+
+    let expected_end = (test.role == Role::Server).then_some(test.deadline);
+    let (sent, received) = tokio::join!(send, receive(&mut reader, test, until, expected_end, meter));
+    Transfer {
+        bytes: sent.bytes + received.bytes,
+        ok: sent.ok && received.ok,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, TokioClock};
+    use crate::live::Meters;
+    use tokio::time::timeout;
+
+    fn stream_test(role: Role, direction: Direction, deadline: Instant, clock: &dyn Clock) -> StreamTest<'_> {
+        StreamTest {
+            role,
+            direction,
+            block_size: 1024,
+            deadline,
+            limiter: None,
+            clock,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_stream() {
+        let meters = Meters::default();
+        let meter = meters.register("test".to_string(), None);
+        // Each reading of the clock moves it 10 ms on, so 1 s takes 99 more readings after the start
+        let clock = ManualClock::stepping(Duration::from_millis(10));
+
+        for direction in [Direction::Upload, Direction::Download, Direction::Bidirectional] {
+            let deadline = clock.now() + Duration::from_secs(1);
+            let (client, server) = tokio::io::duplex(4096);
+            let (mut client, mut server): (BoxStream, BoxStream) = (Box::new(client), Box::new(server));
+            let (client_test, server_test) = (stream_test(Role::Client, direction, deadline, &clock), stream_test(Role::Server, direction, deadline, &clock));
+            let (sent, received) = tokio::join!(run_stream(&mut client, &client_test, &meter), run_stream(&mut server, &server_test, &meter));
+            assert!(sent.ok && received.ok, "{:?}", direction);
+            assert_eq!(sent.bytes, 99 * 1024, "{:?}", direction);
+            assert_eq!(received.bytes, sent.bytes, "{:?}", direction);
+        }
+
+        // An upload that ends before its deadline was aborted by the client
+        let (client, server) = tokio::io::duplex(4096);
+        drop(client);
+        let mut server: BoxStream = Box::new(server);
+        let test = stream_test(Role::Server, Direction::Upload, clock.peek() + Duration::from_secs(10), &clock);
+        assert_eq!(run_stream(&mut server, &test, &meter).await, Transfer { bytes: 0, ok: false });
+
+        // A download client waits for a silent server until the grace period after the deadline is over
+        let clock = ManualClock::new();
+        let (client, _server) = tokio::io::duplex(4096);
+        let mut client: BoxStream = Box::new(client);
+        let test = stream_test(Role::Client, Direction::Download, clock.now() + Duration::from_secs(1), &clock);
+        let receiving = run_stream(&mut client, &test, &meter);
+        tokio::pin!(receiving);
+        clock.advance(Duration::from_secs(2));
+        assert!(timeout(Duration::ZERO, receiving.as_mut()).await.is_err());
+        clock.advance(RECEIVE_GRACE);
+        assert_eq!(receiving.await, Transfer { bytes: 0, ok: true });
+
+        // Likewise both halves of a bidirectional stream to a silent peer, the send half once the
+        // pipe is full
+        let (client, _server) = tokio::io::duplex(4096);
+        let mut client: BoxStream = Box::new(client);
+        let test = stream_test(Role::Client, Direction::Bidirectional, clock.now() + Duration::from_secs(1), &clock);
+        let exchanging = run_stream(&mut client, &test, &meter);
+        tokio::pin!(exchanging);
+        assert!(timeout(Duration::ZERO, exchanging.as_mut()).await.is_err());
+        clock.advance(Duration::from_secs(2));
+        assert!(timeout(Duration::ZERO, exchanging.as_mut()).await.is_err());
+        clock.advance(RECEIVE_GRACE);
+        assert_eq!(exchanging.await, Transfer { bytes: 4096, ok: true });
+
+        // A bidirectional client that closes before the deadline aborted
+        let (client, server) = tokio::io::duplex(4096);
+        drop(client);
+        let mut server: BoxStream = Box::new(server);
+        let test = stream_test(Role::Server, Direction::Bidirectional, clock.now() + Duration::from_secs(10), &clock);
+        assert!(!run_stream(&mut server, &test, &meter).await.ok);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paused_time() {
+        let meters = Meters::default();
+        let meter = meters.register("test".to_string(), None);
+
+        // The server ends an upload that runs over, paused time gets there without waiting 12 s
+        let (_client, server) = tokio::io::duplex(4096);
+        let mut server: BoxStream = Box::new(server);
+        let start = Instant::now();
+        let test = stream_test(Role::Server, Direction::Upload, start + Duration::from_secs(10), &TokioClock);
+        assert_eq!(run_stream(&mut server, &test, &meter).await, Transfer { bytes: 0, ok: true });
+        assert_eq!(start.elapsed().as_secs(), 12);
+    }
+}
@@ -0,0 +1,473 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime, TimeZone};
+use clap::ValueEnum;
+use num_format::Locale;
+use rusqlite::types::{Type, Value};
+use rusqlite::{Connection, OptionalExtension, Row, params, params_from_iter};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    Direction,
+    sink::Measurement,
+    utils::{format_number, median, percentile},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        target TEXT NOT NULL,
+        direction TEXT NOT NULL,
+        block_size_kb INTEGER NOT NULL,
+        duration_secs REAL NOT NULL,
+        bytes INTEGER NOT NULL,
+        latency_ms REAL,
+        errors INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS runs_timestamp ON runs (timestamp_ms);
+    CREATE TABLE IF NOT EXISTS streams (
+        run_id INTEGER NOT NULL REFERENCES runs (id),
+        stream INTEGER NOT NULL,
+        bytes INTEGER NOT NULL,
+        PRIMARY KEY (run_id, stream)
+    );
+";
+
+const COLUMNS: &str = "id, timestamp_ms, target, direction, block_size_kb, duration_secs, bytes, latency_ms, errors";
+
+/// Results database with one row per measurement.
+pub struct Store {
+    connection: Connection,
+}
+
+/// A stored measurement and its row id.
+pub struct Run {
+    pub id: i64,
+    pub measurement: Measurement,
+}
+
+/// Which runs a query returns. Unset fields match every run.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub target: Option<String>,
+    pub direction: Option<Direction>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+}
+
+impl Filter {
+    pub fn matches(&self, measurement: &Measurement) -> bool {
+        self.target.as_ref().is_none_or(|target| *target == measurement.target)
+            && self.direction.is_none_or(|direction| direction == measurement.direction)
+            && self.since.is_none_or(|since| measurement.timestamp >= since)
+            && self.until.is_none_or(|until| measurement.timestamp < until)
+    }
+}
+
+impl Store {
+    /// Opens or creates the database at `path`.
+    pub fn open(path: &Path) -> rusqlite::Result<Store> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Store { connection })
+    }
+
+    pub fn insert(&self, measurement: &Measurement) -> rusqlite::Result<i64> {
+        insert_into(&self.connection, measurement)
+    }
+
+    /// Inserts all `measurements` in one transaction.
+    pub fn insert_all(&mut self, measurements: &[Measurement]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        for measurement in measurements {
+            insert_into(&transaction, measurement)?;
+        }
+        transaction.commit()
+    }
+
+    /// Runs matching `filter`, oldest first.
+    pub fn runs(&self, filter: &Filter) -> rusqlite::Result<Vec<Run>> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(target) = &filter.target {
+            conditions.push("target = ?");
+            values.push(Value::Text(target.clone()));
+        }
+        if let Some(direction) = filter.direction {
+            conditions.push("direction = ?");
+            values.push(Value::Text(format!("{:?}", direction)));
+        }
+        if let Some(since) = filter.since {
+            conditions.push("timestamp_ms >= ?");
+            values.push(Value::Integer(since.timestamp_millis()));
+        }
+        if let Some(until) = filter.until {
+            conditions.push("timestamp_ms < ?");
+            values.push(Value::Integer(until.timestamp_millis()));
+        }
+
+        let condition = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+        let mut statement = self.connection.prepare(&format!("SELECT {} FROM runs{} ORDER BY timestamp_ms, id", COLUMNS, condition))?;
+        let mut runs = statement.query_map(params_from_iter(values), run_from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+        for run in &mut runs {
+            run.measurement.stream_bytes = self.stream_bytes(run.id)?;
+        }
+        Ok(runs)
+    }
+
+    pub fn run(&self, id: i64) -> rusqlite::Result<Option<Run>> {
+        let run = self.connection.query_row(&format!("SELECT {} FROM runs WHERE id = ?1", COLUMNS), [id], run_from_row).optional()?;
+        run.map(|mut run| {
+            run.measurement.stream_bytes = self.stream_bytes(run.id)?;
+            Ok(run)
+        })
+        .transpose()
+    }
+
+    fn stream_bytes(&self, id: i64) -> rusqlite::Result<Vec<usize>> {
+        let mut statement = self.connection.prepare_cached("SELECT bytes FROM streams WHERE run_id = ?1 ORDER BY stream")?;
+        statement.query_map([id], |row| row.get::<_, i64>(0).map(|bytes| bytes as usize))?.collect()
+    }
+}
+
+fn insert_into(connection: &Connection, measurement: &Measurement) -> rusqlite::Result<i64> {
+    connection.execute(
+        "INSERT INTO runs (timestamp_ms, target, direction, block_size_kb, duration_secs, bytes, latency_ms, errors)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            measurement.timestamp.timestamp_millis(),
+            measurement.target,
+            format!("{:?}", measurement.direction),
+            measurement.block_size_kb as i64,
+            measurement.duration_secs,
+            measurement.bytes as i64,
+            measurement.latency_ms,
+            measurement.errors as i64,
+        ],
+    )?;
+    let id = connection.last_insert_rowid();
+
+    let mut statement = connection.prepare_cached("INSERT INTO streams (run_id, stream, bytes) VALUES (?1, ?2, ?3)")?;
+    for (index, bytes) in measurement.stream_bytes.iter().enumerate() {
+        statement.execute(params![id, index as i64 + 1, *bytes as i64])?;
+    }
+    Ok(id)
+}
+
+fn run_from_row(row: &Row) -> rusqlite::Result<Run> {
+    let invalid = |column, e: String| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, e.into());
+    let timestamp = DateTime::from_timestamp_millis(row.get(1)?).ok_or_else(|| invalid(1, "timestamp out of range".to_string()))?;
+    let direction = Direction::from_str(&row.get::<_, String>(3)?, true).map_err(|e| invalid(3, e))?;
+
+    Ok(Run {
+        id: row.get(0)?,
+        measurement: Measurement {
+            timestamp: timestamp.with_timezone(&Local),
+            target: row.get(2)?,
+            direction,
+            block_size_kb: row.get::<_, i64>(4)? as usize,
+            duration_secs: row.get(5)?,
+            bytes: row.get::<_, i64>(6)? as usize,
+            latency_ms: row.get(7)?,
+            errors: row.get::<_, i64>(8)? as usize,
+            stream_bytes: Vec::new(),
+        },
+    })
+}
+
+/// Point in time given as `2024-03-04`, `2024-03-04 10:30[:00]`, RFC 3339, or relative as `30m`, `12h`, `7d`, `2w` ago.
+pub fn parse_time(s: &str) -> Result<DateTime<Local>, String> {
+    let invalid = || format!("Invalid time '{}', expected e.g. 2024-03-04, \"2024-03-04 10:30\" or 7d", s);
+
+    if let Some(unit) = s.chars().last()
+        && let Ok(amount) = s[..s.len() - unit.len_utf8()].parse::<i64>()
+    {
+        let ago = match unit {
+            'm' => ChronoDuration::minutes(amount),
+            'h' => ChronoDuration::hours(amount),
+            'd' => ChronoDuration::days(amount),
+            'w' => ChronoDuration::weeks(amount),
+            _ => return Err(invalid()),
+        };
+        return Ok(Local::now() - ago);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+        .ok_or_else(invalid)?;
+    Local.from_local_datetime(&naive).earliest().ok_or_else(invalid)
+}
+
+/// One side of a comparison: a run id or a time window `FROM..TO`, where either end may be left out.
+#[derive(Clone, Debug)]
+pub enum Selection {
+    Run(i64),
+    Window(Option<DateTime<Local>>, Option<DateTime<Local>>),
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let optional = |time: &str| (!time.is_empty()).then(|| parse_time(time)).transpose();
+        match s.split_once("..") {
+            Some((from, to)) => Ok(Selection::Window(optional(from)?, optional(to)?)),
+            None => s.parse().map(Selection::Run).map_err(|_| format!("Invalid selection '{}', expected a run id or FROM..TO", s)),
+        }
+    }
+}
+
+impl Selection {
+    fn runs(&self, store: &Store, filter: &Filter) -> rusqlite::Result<Vec<Run>> {
+        match self {
+            Selection::Run(id) => Ok(store.run(*id)?.into_iter().collect()),
+            Selection::Window(since, until) => store.runs(&Filter {
+                since: *since,
+                until: *until,
+                ..filter.clone()
+            }),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let time = |time: &Option<DateTime<Local>>| time.map_or("…".to_string(), |time| time.format("%Y-%m-%d %H:%M").to_string());
+        match self {
+            Selection::Run(id) => format!("Lauf {}", id),
+            Selection::Window(since, until) => format!("{} bis {}", time(since), time(until)),
+        }
+    }
+}
+
+/// Throughput figures of several runs of one target and direction.
+pub struct Aggregate {
+    pub runs: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub latency_ms: Option<f64>,
+    pub errors: usize,
+}
+
+impl Aggregate {
+    pub fn new(measurements: &[&Measurement]) -> Self {
+        let mbits: Vec<f64> = measurements.iter().map(|measurement| measurement.mbit_per_sec()).collect();
+        let latencies: Vec<f64> = measurements.iter().filter_map(|measurement| measurement.latency_ms).collect();
+        Aggregate {
+            runs: measurements.len(),
+            mean: mbits.iter().sum::<f64>() / mbits.len() as f64,
+            median: median(&mbits),
+            p95: percentile(&mbits, 95.0),
+            latency_ms: (!latencies.is_empty()).then(|| latencies.iter().sum::<f64>() / latencies.len() as f64),
+            errors: measurements.iter().map(|measurement| measurement.errors).sum(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let locale = Locale::de;
+        let mut text = format!(
+            "{} {}, Mittel {}, Median {}, P95 {} MBit/s",
+            self.runs,
+            if self.runs == 1 { "Lauf" } else { "Läufe" },
+            format_number(self.mean, &locale),
+            format_number(self.median, &locale),
+            format_number(self.p95, &locale)
+        );
+        if let Some(latency_ms) = self.latency_ms {
+            text.push_str(&format!(", Latenz Ø {} ms", format_number(latency_ms, &locale)));
+        }
+        if self.errors > 0 {
+            text.push_str(&format!(", {} fehlerhafte Streams", self.errors));
+        }
+        text
+    }
+}
+
+fn group(runs: &[Run]) -> BTreeMap<(&str, Direction), Vec<&Measurement>> {
+    let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for run in runs {
+        groups.entry((run.measurement.target.as_str(), run.measurement.direction)).or_default().push(&run.measurement);
+    }
+    groups
+}
+
+/// Prints the runs matching `filter`, only the newest `limit` if given.
+pub fn list(store: &Store, filter: &Filter, limit: Option<usize>) -> rusqlite::Result<()> {
+    let runs = store.runs(filter)?;
+    let skip = limit.map_or(0, |limit| runs.len().saturating_sub(limit));
+    let locale = Locale::de;
+
+    println!(
+        "{:>6}  {:<19}  {:<24}  {:<13}  {:>12}  {:>10}  {:>6}",
+        "Nr", "Zeitpunkt", "Adresse", "Richtung", "MBit/s", "Latenz ms", "Fehler"
+    );
+    for run in &runs[skip..] {
+        let measurement = &run.measurement;
+        println!(
+            "{:>6}  {:<19}  {:<24}  {:<13}  {:>12}  {:>10}  {:>6}",
+            run.id,
+            measurement.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            measurement.target,
+            format!("{:?}", measurement.direction),
+            format_number(measurement.mbit_per_sec(), &locale),
+            measurement.latency_ms.map_or("-".to_string(), |latency| format_number(latency, &locale)),
+            measurement.errors
+        );
+    }
+    println!("{} von {} Läufen", runs.len() - skip, runs.len());
+    Ok(())
+}
+
+/// Prints mean, median and 95th percentile per target and direction.
+pub fn stats(store: &Store, filter: &Filter) -> rusqlite::Result<()> {
+    let runs = store.runs(filter)?;
+    if runs.is_empty() {
+        println!("Keine Läufe gefunden");
+    }
+
+    for ((target, direction), measurements) in group(&runs) {
+        let (first, last) = (measurements[0].timestamp, measurements[measurements.len() - 1].timestamp);
+        println!("\n[ERGEBNIS] {} {:?}", target, direction);
+        println!("• {} bis {}", first.format("%Y-%m-%d %H:%M"), last.format("%Y-%m-%d %H:%M"));
+        println!("• {}", Aggregate::new(&measurements).describe());
+    }
+    Ok(())
+}
+
+/// Prints both selections side by side per target and direction, with the change of the median.
+pub fn compare(store: &Store, filter: &Filter, a: &Selection, b: &Selection) -> rusqlite::Result<()> {
+    let (runs_a, runs_b) = (a.runs(store, filter)?, b.runs(store, filter)?);
+    let (groups_a, groups_b) = (group(&runs_a), group(&runs_b));
+    if groups_a.is_empty() && groups_b.is_empty() {
+        println!("Keine Läufe gefunden");
+    }
+
+    let mut keys: Vec<_> = groups_a.keys().chain(groups_b.keys()).copied().collect();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        let (aggregate_a, aggregate_b) = (groups_a.get(&key).map(|m| Aggregate::new(m)), groups_b.get(&key).map(|m| Aggregate::new(m)));
+        println!("\n[VERGLEICH] {} {:?}", key.0, key.1);
+        for (name, selection, aggregate) in [("A", a, &aggregate_a), ("B", b, &aggregate_b)] {
+            let text = aggregate.as_ref().map_or("keine Läufe".to_string(), Aggregate::describe);
+            println!("• {} ({}): {}", name, selection.describe(), text);
+        }
+        if let (Some(aggregate_a), Some(aggregate_b)) = (aggregate_a, aggregate_b) {
+            let change = (aggregate_b.median / aggregate_a.median - 1.0) * 100.0;
+            let sign = if change < 0.0 { "-" } else { "+" };
+            println!("• Änderung Median: {}{} %", sign, format_number(change.abs(), &Locale::de));
+        }
+    }
+    Ok(())
+}
+
+/// Imports a `results.csv` written by the csv sink. Returns the number of imported runs.
+pub fn import_csv(store: &mut Store, path: &Path) -> io::Result<usize> {
+    let measurements = read_csv(path)?;
+    store.insert_all(&measurements).map_err(io::Error::other)?;
+    Ok(measurements.len())
+}
+
+/// Reads a `results.csv` written by the csv sink.
+/// The CSV has no latency, stream errors or streams, and the byte count is rounded to the stored precision.
+pub fn read_csv(path: &Path) -> io::Result<Vec<Measurement>> {
+    let content = fs::read_to_string(path)?;
+    let invalid = |line: usize, what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: invalid {}", path.display(), line + 1, what));
+
+    let mut measurements = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if index == 0 && line.starts_with("Zeitpunkt;") || line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(';').collect();
+        if fields.len() < 6 {
+            return Err(invalid(index, "row"));
+        }
+        let timestamp = NaiveDateTime::parse_from_str(fields[0], "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .and_then(|time| Local.from_local_datetime(&time).earliest())
+            .ok_or_else(|| invalid(index, "timestamp"))?;
+        let direction = Direction::from_str(fields[2], true).map_err(|_| invalid(index, "direction"))?;
+        let block_size_kb = fields[3].parse().map_err(|_| invalid(index, "block size"))?;
+        let duration_secs = parse_german_number(fields[4]).ok_or_else(|| invalid(index, "duration"))?;
+        let mbytes = parse_german_number(fields[5]).ok_or_else(|| invalid(index, "byte count"))?;
+
+        measurements.push(Measurement {
+            timestamp,
+            target: fields[1].to_string(),
+            direction,
+            block_size_kb,
+            duration_secs,
+            bytes: (mbytes * 1_000_000.0).round() as usize,
+            latency_ms: None,
+            errors: 0,
+            stream_bytes: Vec::new(),
+        });
+    }
+
+    Ok(measurements)
+}
+
+/// Reverses [`format_number`] with the German locale, e.g. `1.234,5`.
+fn parse_german_number(s: &str) -> Option<f64> {
+    s.replace('.', "").replace(',', ".").parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(timestamp: &str, direction: Direction, mbit: f64) -> Measurement {
+        let example = Measurement::example(direction, mbit);
+        Measurement {
+            timestamp: parse_time(timestamp).unwrap(),
+            latency_ms: Some(0.5),
+            stream_bytes: vec![example.bytes / 2; 2],
+            ..example
+        }
+    }
+
+    #[test]
+    fn test_store_queries() {
+        let mut store = Store::open(Path::new(":memory:")).unwrap();
+        store
+            .insert_all(&[
+                measurement("2024-03-04 10:00", Direction::Download, 100.0),
+                measurement("2024-03-04 11:00", Direction::Upload, 40.0),
+                measurement("2024-03-05 10:00", Direction::Download, 80.0),
+            ])
+            .unwrap();
+
+        let downloads = store
+            .runs(&Filter {
+                direction: Some(Direction::Download),
+                ..Filter::default()
+            })
+            .unwrap();
+        assert_eq!(downloads.iter().map(|run| run.measurement.mbit_per_sec()).collect::<Vec<_>>(), [100.0, 80.0]);
+
+        let Selection::Window(since, until) = "2024-03-04..2024-03-05".parse().unwrap() else { panic!() };
+        let first_day = store.runs(&Filter { since, until, ..Filter::default() }).unwrap();
+        assert_eq!(first_day.len(), 2);
+
+        let run = store.run(first_day[1].id).unwrap().unwrap();
+        assert_eq!(run.measurement.direction, Direction::Upload);
+        assert_eq!(run.measurement.latency_ms, Some(0.5));
+        assert_eq!(run.measurement.stream_bytes, [2_500_000, 2_500_000]);
+        assert!(store.run(99).unwrap().is_none());
+        assert!(matches!("7".parse(), Ok(Selection::Run(7))));
+        assert!("yesterday".parse::<Selection>().is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Async TCP, TLS and QUIC bandwidth tests, the library behind the `speedtest` command.
+//!
+//! [`Client`] runs one test against a speedtest server, [`Server`] embeds one:
+//!
+//! ```no_run
+//! use speedtest::{Client, Direction, Server};
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let server = Server::new().port(0).one_off(true).start().await?;
+//! let address = server.local_addr().unwrap().to_string();
+//!
+//! let result = Client::new(address).streams(2).duration_secs(5).direction(Direction::Download).run().await?;
+//! println!("{} MBit/s", result.statistics.mbits_per_sec);
+//!
+//! let report = server.wait().await;
+//! assert!(report.succeeded());
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod agent;
+pub mod auth;
+pub mod client;
+pub mod clock;
+pub(crate) mod engine;
+pub mod history;
+pub mod limits;
+pub(crate) mod live;
+pub mod metrics;
+pub mod net;
+pub mod protocol;
+pub mod quic;
+pub mod relay;
+pub mod server;
+pub(crate) mod signals;
+pub mod sink;
+pub mod tls;
+pub mod transport;
+pub(crate) mod tui;
+pub mod utils;
+
+use clap::ValueEnum;
+
+pub use client::{Client, TestResult};
+pub use server::{RunningServer, Server, ServerReport};
+pub use sink::Measurement;
+pub use utils::Statistics;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+#[clap(rename_all = "lowercase")]
+pub enum Direction {
+    Upload,
+    Download,
+    Bidirectional,
+    Quit,
+}
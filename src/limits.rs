@@ -0,0 +1,364 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, broadcast};
+use tokio::time::{Duration, Instant, sleep};
+
+use crate::Direction;
+use crate::protocol::{Reply, Request};
+
+/// Server side caps, `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub max_duration_secs: Option<u64>,
+    pub max_streams: Option<usize>,
+    pub max_sessions: Option<usize>,
+    pub bandwidth: Option<Arc<RateLimiter>>,
+    /// One session at a time, further sessions wait in a queue.
+    pub exclusive: bool,
+}
+
+impl Limits {
+    /// Checks what the client asks for. Returns the rejection reason for the client.
+    pub fn check(&self, request: &Request) -> Result<(), String> {
+        if let Some(max) = self.max_duration_secs
+            && request.duration_secs > max
+        {
+            return Err(format!("duration of {} s exceeds the server maximum of {} s", request.duration_secs, max));
+        }
+        if let Some(max) = self.max_streams
+            && request.streams > max
+        {
+            return Err(format!("{} streams requested, the server allows at most {} per session", request.streams, max));
+        }
+        Ok(())
+    }
+}
+
+/// Sessions currently running on the server and, in exclusive mode, the ones waiting for their turn.
+#[derive(Clone)]
+pub struct Sessions {
+    state: Arc<Mutex<SessionState>>,
+    /// Woken whenever a session ends.
+    ended: Arc<Notify>,
+    completed: broadcast::Sender<SessionSummary>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            state: Arc::default(),
+            ended: Arc::default(),
+            completed: broadcast::channel(16).0,
+        }
+    }
+}
+
+/// What one session did, published when its last stream ends.
+#[derive(Clone, Debug)]
+pub struct SessionSummary {
+    pub id: String,
+    pub direction: Direction,
+    /// Streams the client announced and streams that actually joined.
+    pub announced: usize,
+    pub joined: usize,
+    pub failed: usize,
+    pub bytes: usize,
+    pub duration: Duration,
+}
+
+impl SessionSummary {
+    pub fn succeeded(&self) -> bool {
+        self.failed == 0 && self.joined >= self.announced && self.bytes > 0
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    running: HashMap<String, Running>,
+    queue: VecDeque<Waiting>,
+}
+
+struct Running {
+    direction: Direction,
+    announced: usize,
+    /// Streams currently connected.
+    streams: usize,
+    joined: usize,
+    failed: usize,
+    bytes: usize,
+    started: Instant,
+    ends: Instant,
+}
+
+struct Waiting {
+    id: String,
+    duration: Duration,
+}
+
+impl Sessions {
+    /// Adds one stream to the session of `request`, opening the session if needed.
+    /// The stream leaves the session when the returned guard is dropped.
+    /// Returns the reply for the client if the stream may not start. Sessions of clients that wait
+    /// keep their place in the queue until they start or [`Sessions::leave_queue`] is called.
+    pub fn join(&self, request: &Request, limits: &Limits) -> Result<SessionGuard, Reply> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(session) = state.running.get_mut(&request.session) {
+            if let Some(max) = limits.max_streams
+                && session.streams >= max
+            {
+                return Err(Reply::Error(format!("session already has {} streams, the server allows at most {}", session.streams, max)));
+            }
+            session.streams += 1;
+            session.joined += 1;
+        } else if limits.exclusive {
+            let first_in_line = state.queue.front().is_none_or(|waiting| waiting.id == request.session);
+
+            if !state.running.is_empty() || !first_in_line {
+                return Err(state.enqueue(request, now));
+            }
+            state.queue.pop_front();
+            state.start(request, now);
+        } else {
+            let running = state.running.len();
+            if let Some(max) = limits.max_sessions
+                && running >= max
+            {
+                return Err(Reply::Error(format!("server busy, {} of {} sessions running", running, max)));
+            }
+            state.start(request, now);
+        }
+
+        Ok(SessionGuard {
+            sessions: self.clone(),
+            id: request.session.clone(),
+            bytes: 0,
+            ok: false,
+        })
+    }
+
+    /// Number of running and queued sessions.
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.running.len(), state.queue.len())
+    }
+
+    /// Summaries of all sessions that end from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionSummary> {
+        self.completed.subscribe()
+    }
+
+    pub fn leave_queue(&self, id: &str) {
+        self.state.lock().unwrap().queue.retain(|waiting| waiting.id != id);
+    }
+
+    /// Resolves when a running session ends. Wake-ups may be missed, so callers also poll.
+    pub async fn ended(&self) {
+        self.ended.notified().await;
+    }
+}
+
+impl SessionState {
+    fn start(&mut self, request: &Request, now: Instant) {
+        let session = Running {
+            direction: request.direction,
+            announced: request.streams,
+            streams: 1,
+            joined: 1,
+            failed: 0,
+            bytes: 0,
+            started: now,
+            ends: now + Duration::from_secs(request.duration_secs),
+        };
+        self.running.insert(request.session.clone(), session);
+    }
+
+    /// Tells the client where it stands, keeping a place in line if it waits.
+    fn enqueue(&mut self, request: &Request, now: Instant) -> Reply {
+        let index = match self.queue.iter().position(|waiting| waiting.id == request.session) {
+            Some(index) => index,
+            None if request.wait => {
+                self.queue.push_back(Waiting {
+                    id: request.session.clone(),
+                    duration: Duration::from_secs(request.duration_secs),
+                });
+                self.queue.len() - 1
+            }
+            None => self.queue.len(),
+        };
+
+        let remaining = self.running.values().map(|session| session.ends.saturating_duration_since(now)).max().unwrap_or_default();
+        let ahead: Duration = self.queue.iter().take(index).map(|waiting| waiting.duration).sum();
+
+        Reply::Busy {
+            position: index + 1,
+            wait_secs: (remaining + ahead).as_secs_f64().ceil() as u64,
+        }
+    }
+}
+
+pub struct SessionGuard {
+    sessions: Sessions,
+    id: String,
+    bytes: usize,
+    ok: bool,
+}
+
+impl SessionGuard {
+    /// Result of the stream, a stream dropped without it counts as failed.
+    pub fn record(&mut self, bytes: usize, ok: bool) {
+        self.bytes = bytes;
+        self.ok = ok;
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut state = self.sessions.state.lock().unwrap();
+        let Some(session) = state.running.get_mut(&self.id) else {
+            return;
+        };
+
+        session.streams -= 1;
+        session.bytes += self.bytes;
+        if !self.ok {
+            session.failed += 1;
+        }
+
+        if session.streams == 0
+            && let Some(session) = state.running.remove(&self.id)
+        {
+            let _ = self.sessions.completed.send(SessionSummary {
+                id: self.id.clone(),
+                direction: session.direction,
+                announced: session.announced,
+                joined: session.joined,
+                failed: session.failed,
+                bytes: session.bytes,
+                duration: session.started.elapsed(),
+            });
+            self.sessions.ended.notify_waiters();
+        }
+    }
+}
+
+/// Parses a rate in MBit/s for a [`RateLimiter`], which needs a finite number above 0.
+pub fn parse_mbit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(mbit) if mbit.is_finite() && mbit > 0.0 => Ok(mbit),
+        _ => Err(format!("'{}' is not a rate above 0 MBit/s", s)),
+    }
+}
+
+/// Token bucket shared by all streams of the server.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    /// Available bytes (negative while callers wait for capacity) and time of the last refill.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// # Panics
+    ///
+    /// If the rate is not a finite number above 0, see [`parse_mbit`].
+    pub fn new(mbit_per_sec: f64) -> Self {
+        assert!(mbit_per_sec.is_finite() && mbit_per_sec > 0.0, "Rate must be a finite number of MBit/s above 0, not {}", mbit_per_sec);
+        RateLimiter {
+            bytes_per_sec: mbit_per_sec * 1_000_000.0 / 8.0,
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Waits until `bytes` may be transferred without exceeding the rate.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            // Allows bursts of at most 100 ms worth of data
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.bytes_per_sec).min(self.bytes_per_sec / 10.0);
+            *last = now;
+            *tokens -= bytes as f64;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / self.bytes_per_sec))
+        };
+
+        if let Some(wait) = wait {
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(session: &str) -> Request {
+        Request {
+            direction: Direction::Upload,
+            duration_secs: 10,
+            session: session.to_string(),
+            streams: 2,
+            wait: true,
+            run: None,
+        }
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let limits = Limits {
+            max_streams: Some(2),
+            max_sessions: Some(1),
+            ..Limits::default()
+        };
+        let sessions = Sessions::default();
+
+        let first = sessions.join(&request("a"), &limits).unwrap();
+        let mut second = sessions.join(&request("a"), &limits).unwrap();
+        assert!(sessions.join(&request("a"), &limits).is_err(), "Third stream must be rejected");
+        assert!(sessions.join(&request("b"), &limits).is_err(), "Second session must be rejected");
+
+        let mut completed = sessions.subscribe();
+        drop(first);
+        assert!(completed.try_recv().is_err(), "Session must run until its last stream ends");
+        second.record(1000, true);
+        drop(second);
+        let summary = completed.try_recv().unwrap();
+        assert_eq!((summary.joined, summary.failed, summary.bytes), (2, 1, 1000));
+        assert!(!summary.succeeded());
+
+        assert!(sessions.join(&request("b"), &limits).is_ok(), "Session must end with its last stream");
+    }
+
+    #[test]
+    fn test_parse_mbit() {
+        assert_eq!(parse_mbit("2.5"), Ok(2.5));
+        for invalid in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(parse_mbit(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "above 0")]
+    fn test_rate_limiter_rejects_zero() {
+        RateLimiter::new(0.0);
+    }
+
+    #[test]
+    fn test_exclusive_queue() {
+        let limits = Limits { exclusive: true, ..Limits::default() };
+        let sessions = Sessions::default();
+
+        let running = sessions.join(&request("a"), &limits).unwrap();
+        assert_eq!(sessions.join(&request("b"), &limits).err(), Some(Reply::Busy { position: 1, wait_secs: 10 }));
+        assert_eq!(sessions.join(&request("c"), &limits).err(), Some(Reply::Busy { position: 2, wait_secs: 20 }));
+        let impatient = Request { wait: false, ..request("d") };
+        assert_eq!(sessions.join(&impatient, &limits).err(), Some(Reply::Busy { position: 3, wait_secs: 30 }));
+
+        drop(running);
+        assert!(sessions.join(&request("c"), &limits).is_err(), "Queued sessions must keep their order");
+        sessions.leave_queue("b");
+        assert!(sessions.join(&request("c"), &limits).is_ok(), "Only waiting clients may keep a place");
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+/// Selected `TCP_INFO` values of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpInfo {
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    /// Congestion window in segments.
+    pub cwnd: u32,
+    pub mss: u32,
+    pub retransmits: u32,
+}
+
+/// Duplicate of a TCP socket descriptor, so `TCP_INFO` can be read while the stream is in use elsewhere.
+/// Only available on Linux.
+pub struct SocketProbe {
+    #[cfg(target_os = "linux")]
+    fd: std::os::fd::OwnedFd,
+}
+
+impl SocketProbe {
+    #[cfg(target_os = "linux")]
+    pub fn new(stream: &TcpStream) -> Option<Self> {
+        use std::os::fd::AsFd;
+        stream.as_fd().try_clone_to_owned().ok().map(|fd| SocketProbe { fd })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_stream: &TcpStream) -> Option<Self> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn tcp_info(&self) -> Option<TcpInfo> {
+        use std::os::fd::AsRawFd;
+
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // SAFETY: `info` is a valid buffer of `len` bytes and the descriptor is owned by `self`
+        let result = unsafe { libc::getsockopt(self.fd.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut _ as *mut libc::c_void, &mut len) };
+
+        (result == 0).then(|| TcpInfo {
+            rtt: Duration::from_micros(info.tcpi_rtt.into()),
+            rtt_var: Duration::from_micros(info.tcpi_rttvar.into()),
+            cwnd: info.tcpi_snd_cwnd,
+            mss: info.tcpi_snd_mss,
+            retransmits: info.tcpi_total_retrans,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn tcp_info(&self) -> Option<TcpInfo> {
+        None
+    }
+}
+
+/// Byte counter of one running stream, read by the terminal UI.
+pub struct Meter {
+    pub label: String,
+    bytes: AtomicU64,
+    probe: Option<SocketProbe>,
+}
+
+impl Meter {
+    pub fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn tcp_info(&self) -> Option<TcpInfo> {
+        self.probe.as_ref().and_then(SocketProbe::tcp_info)
+    }
+}
+
+/// The meters of all running streams. A stream's meter goes away with the last handle of its task.
+#[derive(Default)]
+pub struct Meters {
+    meters: Mutex<Vec<Weak<Meter>>>,
+}
+
+impl Meters {
+    pub fn register(&self, label: String, probe: Option<SocketProbe>) -> Arc<Meter> {
+        let meter = Arc::new(Meter {
+            label,
+            bytes: AtomicU64::new(0),
+            probe,
+        });
+        let mut meters = self.meters.lock().unwrap();
+        meters.retain(|meter| meter.strong_count() > 0);
+        meters.push(Arc::downgrade(&meter));
+        meter
+    }
+
+    /// Meters of the streams still running, in the order they started.
+    pub fn running(&self) -> Vec<Arc<Meter>> {
+        self.meters.lock().unwrap().iter().filter_map(Weak::upgrade).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_meters() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let meters = Meters::default();
+        let meter = meters.register("Stream 1".to_string(), SocketProbe::new(&client));
+        let other = meters.register("Stream 2".to_string(), None);
+
+        client.write_all(&[0u8; 1000]).await.unwrap();
+        server.read_exact(&mut [0u8; 1000]).await.unwrap();
+        meter.add(1000);
+        assert_eq!(meter.bytes(), 1000);
+        if cfg!(target_os = "linux") {
+            assert!(meter.tcp_info().is_some_and(|info| info.mss > 0));
+        }
+
+        drop(other);
+        let running = meters.running();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].label, "Stream 1");
+    }
+}
@@ -1,41 +1,264 @@
-mod client;
+mod alert;
+mod config;
 mod file;
-mod server;
-mod utils;
-
-use clap::{Parser, Subcommand, ValueEnum};
-
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
-#[clap(rename_all = "lowercase")]
-enum Direction {
-    Upload,
-    Download,
-    Bidirectional,
-    Quit,
-}
+mod monitor;
+mod report;
+mod schedule;
+mod stop;
+mod sweep;
+
+use agent::ControlOptions;
+use alert::{AlertOptions, Webhook};
+use auth::{Key, ServerAuth};
+use chrono::{DateTime, Local};
+use clap::{Parser, Subcommand, ValueEnum, builder::TypedValueParser};
+use history::{Filter, Selection, Store};
+use limits::{Limits, RateLimiter};
+use monitor::{MonitorOptions, TestKind};
+use net::{AddressFamily, BindAddress, BusyPolicy, ConnectOptions, ListenAddress};
+use protocol::Transport;
+use relay::{Impairment, RelayOptions};
+use schedule::{Cron, Schedule};
+use server::{ServerOptions, Settings};
+use sink::Sink;
+use speedtest::clock::TokioClock;
+use speedtest::{Direction, agent, auth, client, history, limits, net, protocol, quic, relay, server, sink, tls};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use sweep::{Steps, SweepOptions};
+use tls::{Identity, TlsClientOptions, Verification};
 
 #[derive(Parser)]
 #[command(name = "speedtest", version, about = "Async TCP Bandwidth Tester in Rust (with Tokio)")]
 struct Args {
+    #[arg(
+        long,
+        global = true,
+        env = "SPEEDTEST_CONFIG",
+        help = "TOML config file with [defaults] and [profiles.NAME] (default: ./speedtest.toml, then ~/.config/speedtest/config.toml)"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(long, global = true, help = "Profile of the config file to use, its `command` runs if none is given")]
+    profile: Option<String>,
+
     #[command(subcommand)]
-    command: Option<Command>,
+    command: Command,
+}
+
+/// How the client reaches the server, shared by all client side commands.
+#[derive(clap::Args)]
+struct ConnectArgs {
+    #[arg(short = '4', long, conflicts_with = "ipv6", help = "Use IPv4 addresses only")]
+    ipv4: bool,
+
+    #[arg(short = '6', long, help = "Use IPv6 addresses only")]
+    ipv6: bool,
+
+    #[arg(long, help = "Local address for the streams as IP or IP:PORT (the port is counted up per stream)")]
+    bind: Option<BindAddress>,
+
+    #[arg(long, help = "Network interface to bind the streams to (Linux only)")]
+    interface: Option<String>,
+
+    #[arg(long, help = "Encrypt all streams with TLS")]
+    tls: bool,
+
+    #[arg(long, conflicts_with = "tls", help = "Run all streams inside one QUIC connection")]
+    quic: bool,
+
+    #[arg(long, help = "PEM CA certificate to verify the server with --tls or --quic (default: the bundled web roots)")]
+    tls_ca: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "tls_ca", help = "Accept any server certificate with --tls or --quic, e.g. one the server generated on the fly")]
+    tls_insecure: bool,
+
+    #[arg(long, help = "Server name for certificate verification with --tls or --quic (default: host part of --address)")]
+    tls_server_name: Option<String>,
+
+    #[arg(long, env = "SPEEDTEST_PSK", hide_env_values = true, help = "Pre-shared key answering the server's challenge (the admin key for quit)")]
+    psk: Option<String>,
+
+    #[arg(long, conflicts_with = "psk", help = "File containing the pre-shared key")]
+    psk_file: Option<PathBuf>,
+
+    #[arg(long, help = "Wait in the queue while the server is busy with another session")]
+    wait: bool,
+
+    #[arg(long, conflicts_with = "wait", help = "Try again this many times while the server is busy")]
+    retries: Option<u32>,
+}
+
+impl ConnectArgs {
+    fn into_options(self) -> ConnectOptions {
+        let verification = match (self.tls_ca, self.tls_insecure) {
+            (Some(path), _) => Verification::Ca(path),
+            (None, true) => Verification::Insecure,
+            (None, false) => Verification::WebRoots,
+        };
+        let quic = self.quic.then(|| quic::client_config(&verification, self.tls_server_name.clone()).expect("Failed to set up QUIC"));
+        let tls = self.tls.then(|| TlsClientOptions {
+            config: tls::client_config(&verification).expect("Failed to set up TLS"),
+            server_name: self.tls_server_name,
+        });
+
+        ConnectOptions {
+            family: AddressFamily::from_flags(self.ipv4, self.ipv6),
+            bind: self.bind,
+            interface: self.interface,
+            tls,
+            quic,
+            psk: Key::load(self.psk, self.psk_file.as_deref()).expect("Failed to load pre-shared key"),
+            busy: match (self.wait, self.retries) {
+                (true, _) => BusyPolicy::Wait,
+                (false, Some(retries)) => BusyPolicy::Retry(retries),
+                (false, None) => BusyPolicy::Fail,
+            },
+            probe: false,
+        }
+    }
+}
+
+/// When the monitor raises an alert and where it goes.
+#[derive(clap::Args)]
+struct AlertArgs {
+    #[arg(long, help = "Alert when a network test reaches less than this many MBit/s")]
+    min_mbit: Option<f64>,
+
+    #[arg(long, help = "Alert when the mean connect latency exceeds this many milliseconds")]
+    max_latency_ms: Option<f64>,
+
+    #[arg(long, help = "Alert when more streams fail than this (a test that cannot run fails all of them)")]
+    max_errors: Option<usize>,
+
+    #[arg(long, help = "Alert when throughput drops this many percent below the median of the recent runs")]
+    max_drop_percent: Option<f64>,
+
+    #[arg(long, default_value = "10", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), help = "Recent runs per direction forming the baseline for --max-drop-percent")]
+    baseline_runs: usize,
+
+    #[arg(long, help = "Shell command run on every alert, with the details in SPEEDTEST_ALERT* variables")]
+    alert_command: Option<String>,
+
+    #[arg(long, help = "URL to POST every alert to as JSON, http:// or https://")]
+    alert_webhook: Option<Webhook>,
+}
+
+impl AlertArgs {
+    fn into_options(self) -> AlertOptions {
+        AlertOptions {
+            min_mbit: self.min_mbit,
+            max_latency_ms: self.max_latency_ms,
+            max_errors: self.max_errors,
+            max_drop_percent: self.max_drop_percent,
+            baseline_runs: self.baseline_runs,
+            command: self.alert_command,
+            webhook: self.alert_webhook,
+        }
+    }
 }
 
+// Parsed once at startup, the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
+    /// Serves tests. SIGHUP reloads the key and certificate files, limits and other options need a restart
     Server {
         #[arg(short, long, default_value = "4000")]
         port: u16,
 
+        #[arg(long, help = "Local address to listen on as IP, IP:PORT or unix:/path (default: dual-stack on all interfaces)")]
+        bind: Option<ListenAddress>,
+
+        #[arg(long, help = "Network interface to bind the listener to (Linux only)")]
+        interface: Option<String>,
+
+        #[arg(long, help = "Encrypt all streams with TLS")]
+        tls: bool,
+
+        #[arg(long, help = "Also accept QUIC connections on the same port (UDP)")]
+        quic: bool,
+
+        #[arg(long, requires = "tls_key", help = "PEM certificate chain for TLS and QUIC (default: self-signed, generated on the fly)")]
+        tls_cert: Option<PathBuf>,
+
+        #[arg(long, requires = "tls_cert", help = "PEM private key for --tls-cert")]
+        tls_key: Option<PathBuf>,
+
+        #[arg(long, env = "SPEEDTEST_PSK", hide_env_values = true, help = "Require this pre-shared key for test sessions")]
+        psk: Option<String>,
+
+        #[arg(long, conflicts_with = "psk", help = "File containing the pre-shared key for test sessions")]
+        psk_file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            env = "SPEEDTEST_ADMIN_PSK",
+            hide_env_values = true,
+            help = "Require this key for administrative commands like quit (default: the session key)"
+        )]
+        admin_psk: Option<String>,
+
+        #[arg(long, conflicts_with = "admin_psk", help = "File containing the key for administrative commands")]
+        admin_psk_file: Option<PathBuf>,
+
+        #[arg(long, help = "Reject tests asking for a longer duration in seconds")]
+        max_duration_secs: Option<u64>,
+
+        #[arg(short = 'd', long, hide = true, conflicts_with = "max_duration_secs", help = "Deprecated alias of --max-duration-secs")]
+        duration_secs: Option<u64>,
+
+        #[arg(long, help = "Reject sessions with more parallel streams")]
+        max_streams: Option<usize>,
+
+        #[arg(long, help = "Reject new sessions while this many are running")]
+        max_sessions: Option<usize>,
+
+        #[arg(long, conflicts_with = "max_sessions", help = "Run one session at a time, further clients are told their queue position")]
+        exclusive: bool,
+
+        #[arg(long, value_parser = limits::parse_mbit, help = "Cap the throughput of all streams together in MBit/s")]
+        max_bandwidth_mbit: Option<f64>,
+
+        #[arg(long, help = "Exit after the first session, with exit code 1 if it failed")]
+        one_off: bool,
+
+        #[arg(long, default_value = "30", help = "Seconds running streams get to finish on SIGINT, SIGTERM or quit")]
+        drain_timeout_secs: u64,
+
+        #[arg(long, value_name = "IP:PORT", help = "Serve Prometheus metrics over HTTP at /metrics on this address")]
+        metrics: Option<SocketAddr>,
+
         #[arg(short = 'b', long, default_value = "64")]
         block_size_kb: usize,
 
-        #[arg(short = 'd', long, default_value = "10")]
-        duration_secs: u64,
+        #[arg(long, help = "Show live throughput graphs of the running streams, if stdout is a terminal")]
+        tui: bool,
+
+        #[arg(long, help = "Run tests against other servers when a controller asks (see control), needs --psk or --admin-psk")]
+        agent: bool,
+
+        #[arg(long, requires = "agent", help = "CA certificate (PEM) the agent checks servers against with TLS or QUIC (default: the bundled web roots)")]
+        agent_tls_ca: Option<PathBuf>,
+
+        #[arg(long, requires = "agent", conflicts_with = "agent_tls_ca", help = "Let the agent accept any server certificate with TLS or QUIC")]
+        agent_tls_insecure: bool,
     },
     Client {
-        #[arg(short, long)]
-        address: String,
+        #[arg(short, long, required_unless_present = "targets", help = "Server address as HOST:PORT or unix:/path, can be repeated")]
+        address: Vec<String>,
+
+        #[arg(long, help = "File with further server addresses, one per line")]
+        targets: Option<PathBuf>,
+
+        #[arg(long, help = "Test all servers at the same time instead of one after another")]
+        concurrent: bool,
+
+        #[command(flatten)]
+        connect: ConnectArgs,
 
         #[arg(short = 't', long, default_value = "4")]
         threads: usize,
@@ -48,11 +271,21 @@ enum Command {
 
         #[arg(long, value_enum, default_value = "upload")]
         direction: Direction,
+
+        #[arg(long, default_value = "csv:results.csv", help = "Write the result to csv:PATH, jsonl:PATH or sqlite:PATH (for history), can be repeated")]
+        sink: Vec<Sink>,
+
+        #[arg(long, conflicts_with = "concurrent", help = "Show live throughput graphs while the test runs, if stdout is a terminal")]
+        tui: bool,
     },
-    Loop {
-        #[arg(short, long)]
+    /// Runs test cycles on a schedule and writes every result to the sinks
+    Monitor {
+        #[arg(short, long, help = "Server address as HOST:PORT or unix:/path")]
         address: String,
 
+        #[command(flatten)]
+        connect: ConnectArgs,
+
         #[arg(short = 't', long, default_value = "4")]
         threads: usize,
 
@@ -62,55 +295,492 @@ enum Command {
         #[arg(short = 'd', long, default_value = "10")]
         duration_secs: u64,
 
-        #[arg(short = 'p', long)]
-        path: String,
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "download,upload,file", help = "Tests of each cycle, in order")]
+        tests: Vec<TestKind>,
+
+        #[arg(long, help = "Start a cycle every N seconds (default: one after the other)")]
+        interval_secs: Option<u64>,
+
+        #[arg(long, conflicts_with = "interval_secs", help = "Start cycles on a cron-like schedule, e.g. \"*/15 * * * *\"")]
+        cron: Option<Cron>,
+
+        #[arg(long, help = "Stop after this many cycles")]
+        iterations: Option<u64>,
+
+        #[arg(long, default_value = "0", help = "Delay every cycle start randomly by up to N seconds")]
+        jitter_secs: u64,
+
+        #[arg(long, default_value = "1", help = "Pause between the tests of a cycle in seconds")]
+        pause_secs: u64,
+
+        #[arg(short = 'p', long, default_value = "./testfile.txt", help = "File for the file test")]
+        path: PathBuf,
 
         #[arg(short = 's', long, default_value = "100", help = "Maximum size of file to write and read in MB (default: 100 MB)")]
         file_size_mb: usize,
+
+        #[arg(long, default_value = "csv:results.csv", help = "Write results to csv:PATH, jsonl:PATH or sqlite:PATH (for history), can be repeated")]
+        sink: Vec<Sink>,
+
+        #[command(flatten)]
+        alerts: AlertArgs,
+    },
+    /// Runs every combination of streams, block sizes, durations and directions and compares them
+    Sweep {
+        #[arg(short, long, help = "Server address as HOST:PORT or unix:/path")]
+        address: String,
+
+        #[command(flatten)]
+        connect: ConnectArgs,
+
+        #[arg(short = 't', long, default_value = "1,2,4,8", help = "Parallel streams, a list like 1,2,4 or ranges like 1..8, 1..16*2 or 2..10+2")]
+        threads: Steps,
+
+        #[arg(short = 'b', long, default_value = "16,64,256", help = "Block sizes in KB, list or ranges as for --threads")]
+        block_size_kb: Steps,
+
+        #[arg(short = 'd', long, default_value = "10", help = "Test durations in seconds, list or ranges as for --threads")]
+        duration_secs: Steps,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "download,upload",
+            value_parser = clap::builder::PossibleValuesParser::new(["upload", "download", "bidirectional"]).map(|s| Direction::from_str(&s, true).unwrap()),
+            help = "Directions to test"
+        )]
+        directions: Vec<Direction>,
+
+        #[arg(long, default_value = "1", help = "Pause between the runs in seconds")]
+        pause_secs: u64,
+
+        #[arg(long, default_value = "csv:results.csv", help = "Write every result to csv:PATH, jsonl:PATH or sqlite:PATH (for history), can be repeated")]
+        sink: Vec<Sink>,
+    },
+    /// Has an agent (server --agent) test against another server and collects the result
+    Control {
+        #[arg(short, long, help = "Agent address as HOST:PORT or unix:/path")]
+        address: String,
+
+        #[command(flatten)]
+        connect: ConnectArgs,
+
+        #[arg(long, help = "Server the agent tests against, as HOST:PORT seen from the agent")]
+        target: String,
+
+        #[arg(long, value_enum, default_value = "tcp", help = "How the agent connects to the target")]
+        transport: Transport,
+
+        #[arg(short = 't', long, default_value = "4")]
+        threads: usize,
+
+        #[arg(short = 'b', long, default_value = "64")]
+        block_size_kb: usize,
+
+        #[arg(short = 'd', long, default_value = "10")]
+        duration_secs: u64,
+
+        #[arg(
+            long,
+            default_value = "upload",
+            value_parser = clap::builder::PossibleValuesParser::new(["upload", "download", "bidirectional"]).map(|s| Direction::from_str(&s, true).unwrap())
+        )]
+        direction: Direction,
+
+        #[arg(long, default_value = "csv:results.csv", help = "Write the result to csv:PATH, jsonl:PATH or sqlite:PATH (for history), can be repeated")]
+        sink: Vec<Sink>,
+    },
+    /// Forwards to a server and adds delay, jitter, a bandwidth cap and UDP loss, to emulate a WAN link on one machine
+    Relay {
+        #[arg(short, long, default_value = "4001")]
+        port: u16,
+
+        #[arg(long, help = "Local address to listen on as IP, IP:PORT or unix:/path (default: dual-stack on all interfaces)")]
+        bind: Option<ListenAddress>,
+
+        #[arg(long, help = "Server to forward to, as HOST:PORT or unix:/path")]
+        target: String,
+
+        #[arg(long, help = "Also relay UDP datagrams (QUIC) on the same port")]
+        quic: bool,
+
+        #[arg(long, default_value = "0", help = "Delay added in each direction in milliseconds")]
+        delay_ms: u64,
+
+        #[arg(long, default_value = "0", help = "Vary the delay randomly by up to this many milliseconds")]
+        jitter_ms: u64,
+
+        #[arg(long, value_parser = limits::parse_mbit, help = "Cap each direction at this many MBit/s, shared by all connections")]
+        bandwidth_mbit: Option<f64>,
+
+        #[arg(long, default_value = "0", requires = "quic", help = "Drop this share of the UDP datagrams in each direction, in percent")]
+        loss_percent: f64,
+    },
+    /// Queries the results stored by a sqlite sink
+    History {
+        #[arg(long, default_value = "results.db", help = "Database written by the sqlite sink")]
+        db: PathBuf,
+
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Writes an HTML report with throughput charts of stored results
+    Report {
+        #[arg(short, long, default_value = "csv:results.csv", help = "Results to read, csv:PATH or sqlite:PATH")]
+        input: Sink,
+
+        #[arg(short, long, default_value = "report.html")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// Lists the runs, oldest first
+    List {
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(short = 'n', long, help = "Show only the newest N runs")]
+        limit: Option<usize>,
+    },
+    /// Shows mean, median and 95th percentile of the throughput per address and direction
+    Stats {
+        #[command(flatten)]
+        filter: FilterArgs,
     },
+    /// Compares two time windows or runs, each given as FROM..TO (either end optional) or run number
+    Compare {
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        a: Selection,
+
+        b: Selection,
+    },
+    /// Imports a results.csv written by a csv sink
+    Import { csv: PathBuf },
+}
+
+/// Which stored runs a history command looks at.
+#[derive(clap::Args)]
+struct FilterArgs {
+    #[arg(short, long, help = "Only runs against this address")]
+    address: Option<String>,
+
+    #[arg(long, value_enum, help = "Only runs in this direction")]
+    direction: Option<Direction>,
+
+    #[arg(long, value_parser = history::parse_time, help = "Only runs at or after this time, e.g. 2024-03-04, \"2024-03-04 10:30\" or 7d (ago)")]
+    since: Option<DateTime<Local>>,
+
+    #[arg(long, value_parser = history::parse_time, help = "Only runs before this time")]
+    until: Option<DateTime<Local>>,
+}
+
+impl FilterArgs {
+    fn into_filter(self) -> Filter {
+        Filter {
+            target: self.address,
+            direction: self.direction,
+            since: self.since,
+            until: self.until,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
-
-    match args.command.unwrap_or(
-        /*
-            Command::Server {
-            port: 4000,
-            block_size_kb: 64,
-            duration_secs: 10,
-        }*/
-        Command::Loop {
-            address: "127.0.0.1:4000".to_string(),
-            threads: 4,
-            block_size_kb: 100,
-            duration_secs: 10,
-            path: "./testfile.txt".to_string(),
-            file_size_mb: 10 * 1024 * 1024,
-        },
-    ) {
-        Command::Server { port, block_size_kb, duration_secs } => {
-            server::run_server(port, block_size_kb, duration_secs).await;
+    let args: Args = config::parse_args();
+
+    match args.command {
+        Command::Server {
+            port,
+            bind,
+            interface,
+            tls,
+            quic,
+            tls_cert,
+            tls_key,
+            psk,
+            psk_file,
+            admin_psk,
+            admin_psk_file,
+            mut max_duration_secs,
+            duration_secs,
+            max_streams,
+            max_sessions,
+            exclusive,
+            max_bandwidth_mbit,
+            one_off,
+            drain_timeout_secs,
+            metrics,
+            block_size_kb,
+            tui,
+            agent,
+            agent_tls_ca,
+            agent_tls_insecure,
+        } => {
+            if let Some(secs) = duration_secs {
+                eprintln!("--duration-secs is deprecated for the server, use --max-duration-secs");
+                max_duration_secs = Some(secs);
+            }
+
+            // Generated once, so a reload keeps it and TLS and QUIC present the same one
+            let tls = tls || tls_cert.is_some();
+            let generated = ((tls || quic) && tls_cert.is_none()).then(|| Identity::generate().expect("Failed to generate a TLS certificate"));
+            if generated.is_some() {
+                println!("Using a self-signed TLS certificate generated on the fly");
+            }
+
+            // Everything read from files, SIGHUP loads it again. Limits and other options stay as given.
+            let load_settings = move || -> io::Result<Settings> {
+                let identity = match (&tls_cert, &tls_key, &generated) {
+                    (Some(cert), Some(key), _) => Some(Identity::load(cert, key)?),
+                    (_, _, generated) => generated.clone(),
+                };
+                Ok(Settings {
+                    tls_config: identity.as_ref().filter(|_| tls).map(tls::server_config).transpose()?,
+                    quic_config: identity.as_ref().filter(|_| quic).map(quic::server_config).transpose()?,
+                    auth: ServerAuth {
+                        session: Key::load(psk.clone(), psk_file.as_deref())?,
+                        admin: Key::load(admin_psk.clone(), admin_psk_file.as_deref())?,
+                    },
+                })
+            };
+            let limits = Limits {
+                max_duration_secs,
+                max_streams,
+                max_sessions,
+                bandwidth: max_bandwidth_mbit.map(|mbit| Arc::new(RateLimiter::new(mbit))),
+                exclusive,
+            };
+
+            let succeeded = server::run_server(
+                ServerOptions {
+                    bind,
+                    interface,
+                    port,
+                    block_size_kb,
+                    load_settings: Box::new(load_settings),
+                    limits,
+                    one_off,
+                    drain_timeout: Duration::from_secs(drain_timeout_secs),
+                    metrics,
+                    agent,
+                    agent_verification: match (agent_tls_ca, agent_tls_insecure) {
+                        (Some(ca), _) => Verification::Ca(ca),
+                        (None, true) => Verification::Insecure,
+                        (None, false) => Verification::WebRoots,
+                    },
+                    verbose: true,
+                    probe: false,
+                    signals: true,
+                    clock: Arc::new(TokioClock),
+                },
+                tui,
+            )
+            .await;
+            if !succeeded {
+                std::process::exit(1);
+            }
         }
         Command::Client {
-            address,
+            mut address,
+            targets,
+            concurrent,
+            connect,
             threads,
             block_size_kb,
             duration_secs,
             direction,
+            sink,
+            tui,
         } => {
-            client::run_client(address, threads, block_size_kb, duration_secs, direction).await;
+            if let Some(path) = targets {
+                address.extend(client::read_targets(&path).expect("Failed to read targets file"));
+            }
+            if address.is_empty() {
+                eprintln!("No server address given");
+                std::process::exit(2);
+            }
+            let options = connect.into_options();
+            let measurements = client::run_targets(&address, concurrent, |target| {
+                client::run_client(target, options.clone(), threads, block_size_kb, duration_secs, direction, tui)
+            })
+            .await;
+            if address.len() > 1 {
+                client::print_summary(&address, &measurements, concurrent);
+            }
+
+            for measurement in measurements.iter().flatten() {
+                for sink in &sink {
+                    sink.write(measurement).expect("Failed to write result");
+                }
+            }
         }
-        Command::Loop {
+        Command::Monitor {
             address,
+            connect,
             threads,
             block_size_kb,
             duration_secs,
+            tests,
+            interval_secs,
+            cron,
+            iterations,
+            jitter_secs,
+            pause_secs,
             path,
             file_size_mb,
+            sink,
+            alerts,
+        } => {
+            let schedule = match (interval_secs, cron) {
+                (Some(secs), _) => Schedule::Interval(Duration::from_secs(secs)),
+                (None, Some(cron)) => Schedule::Cron(cron),
+                (None, None) => Schedule::Continuous,
+            };
+
+            monitor::run_monitor(MonitorOptions {
+                address,
+                connect: connect.into_options(),
+                threads,
+                block_size_kb,
+                duration_secs,
+                tests,
+                schedule,
+                iterations,
+                jitter: Duration::from_secs(jitter_secs),
+                pause: Duration::from_secs(pause_secs),
+                path,
+                file_size_mb,
+                sinks: sink,
+                alerts: alerts.into_options(),
+            })
+            .await;
+        }
+        Command::Sweep {
+            address,
+            connect,
+            threads,
+            block_size_kb,
+            duration_secs,
+            directions,
+            pause_secs,
+            sink,
+        } => {
+            sweep::run_sweep(SweepOptions {
+                address,
+                connect: connect.into_options(),
+                threads,
+                block_sizes_kb: block_size_kb,
+                durations_secs: duration_secs,
+                directions,
+                pause: Duration::from_secs(pause_secs),
+                sinks: sink,
+            })
+            .await;
+        }
+        Command::Control {
+            address,
+            connect,
+            target,
+            transport,
+            threads,
+            block_size_kb,
+            duration_secs,
+            direction,
+            sink,
         } => {
-            client::run_client_loop(address, threads, block_size_kb, duration_secs, &path, file_size_mb).await;
+            let measurement = agent::run_control(ControlOptions {
+                agent: address,
+                connect: connect.into_options(),
+                target,
+                transport,
+                threads,
+                block_size_kb,
+                duration_secs,
+                direction,
+            })
+            .await;
+            let Some(measurement) = measurement else {
+                std::process::exit(1);
+            };
+            for sink in &sink {
+                sink.write(&measurement).expect("Failed to write result");
+            }
+        }
+        Command::Relay {
+            port,
+            bind,
+            target,
+            quic,
+            delay_ms,
+            jitter_ms,
+            bandwidth_mbit,
+            loss_percent,
+        } => {
+            if !(0.0..=100.0).contains(&loss_percent) {
+                eprintln!("--loss-percent must be between 0 and 100");
+                std::process::exit(2);
+            }
+            relay::run_relay(RelayOptions {
+                bind,
+                port,
+                target,
+                udp: quic,
+                impairment: Impairment {
+                    delay: Duration::from_millis(delay_ms),
+                    jitter: Duration::from_millis(jitter_ms),
+                    bandwidth_mbit,
+                    loss: loss_percent / 100.0,
+                },
+                verbose: true,
+            })
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to start relay: {}", e);
+                std::process::exit(2);
+            });
+        }
+        Command::History { db, command } => {
+            let mut store = Store::open(&db).expect("Failed to open the results database");
+            match command {
+                HistoryCommand::List { filter, limit } => history::list(&store, &filter.into_filter(), limit),
+                HistoryCommand::Stats { filter } => history::stats(&store, &filter.into_filter()),
+                HistoryCommand::Compare { filter, a, b } => history::compare(&store, &filter.into_filter(), &a, &b),
+                HistoryCommand::Import { csv } => {
+                    let count = history::import_csv(&mut store, &csv).expect("Failed to import CSV");
+                    println!("Imported {} runs from {} into {}", count, csv.display(), db.display());
+                    Ok(())
+                }
+            }
+            .expect("Failed to query the results database");
+        }
+        Command::Report { input, output, filter } => {
+            let filter = filter.into_filter();
+            let measurements: Vec<_> = match input {
+                Sink::Sqlite(path) => {
+                    let store = Store::open(&path).expect("Failed to open the results database");
+                    let runs = store.runs(&filter).expect("Failed to query the results database");
+                    runs.into_iter().map(|run| run.measurement).collect()
+                }
+                Sink::Csv(path) => {
+                    let measurements = history::read_csv(&path).expect("Failed to read CSV");
+                    measurements.into_iter().filter(|measurement| filter.matches(measurement)).collect()
+                }
+                Sink::Jsonl(_) => {
+                    eprintln!("Reports can only be made from sqlite:PATH or csv:PATH");
+                    std::process::exit(2);
+                }
+            };
+
+            std::fs::write(&output, report::render(&measurements)).expect("Failed to write report");
+            println!("Report with {} results written to {}", measurements.len(), output.display());
         }
     }
 }
@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+use crate::Direction;
+use crate::limits::{SessionSummary, Sessions};
+
+/// Upper bounds of the session throughput histogram in MBit/s.
+const THROUGHPUT_BUCKETS: [f64; 12] = [1.0, 10.0, 50.0, 100.0, 250.0, 500.0, 1_000.0, 2_500.0, 5_000.0, 10_000.0, 25_000.0, 50_000.0];
+
+/// Largest HTTP request head accepted by the metrics endpoint.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Why the server turned a connection away, used as metric label.
+#[derive(Copy, Clone, Debug)]
+pub enum Rejection {
+    Invalid,
+    Auth,
+    Limit,
+    Busy,
+    Shutdown,
+}
+
+impl Rejection {
+    const ALL: [Rejection; 5] = [Rejection::Invalid, Rejection::Auth, Rejection::Limit, Rejection::Busy, Rejection::Shutdown];
+
+    fn label(self) -> &'static str {
+        match self {
+            Rejection::Invalid => "invalid",
+            Rejection::Auth => "auth",
+            Rejection::Limit => "limit",
+            Rejection::Busy => "busy",
+            Rejection::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Counters of one server, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// Bytes per direction, indexed by [`direction_index`].
+    bytes: [AtomicU64; 3],
+    /// Finished streams and their summed duration.
+    streams: AtomicU64,
+    stream_duration: Mutex<Duration>,
+    active_streams: AtomicUsize,
+    rejected: [AtomicU64; Rejection::ALL.len()],
+    sessions: Mutex<SessionStats>,
+}
+
+#[derive(Default)]
+struct SessionStats {
+    succeeded: u64,
+    failed: u64,
+    buckets: [u64; THROUGHPUT_BUCKETS.len()],
+    mbit_sum: f64,
+}
+
+fn direction_index(direction: Direction) -> Option<usize> {
+    match direction {
+        Direction::Upload => Some(0),
+        Direction::Download => Some(1),
+        Direction::Bidirectional => Some(2),
+        Direction::Quit => None,
+    }
+}
+
+impl Metrics {
+    pub fn stream_started(&self) {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_finished(&self, direction: Direction, bytes: usize, duration: Duration) {
+        self.active_streams.fetch_sub(1, Ordering::Relaxed);
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        if let Some(index) = direction_index(direction) {
+            self.bytes[index].fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        *self.stream_duration.lock().unwrap() += duration;
+    }
+
+    pub fn rejected(&self, reason: Rejection) {
+        self.rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_finished(&self, summary: &SessionSummary) {
+        let mbit = summary.bytes as f64 * 8.0 / 1_000_000.0 / summary.duration.as_secs_f64().max(f64::EPSILON);
+        let mut sessions = self.sessions.lock().unwrap();
+        match summary.succeeded() {
+            true => sessions.succeeded += 1,
+            false => sessions.failed += 1,
+        }
+        sessions.mbit_sum += mbit;
+        for (bucket, bound) in sessions.buckets.iter_mut().zip(THROUGHPUT_BUCKETS) {
+            if mbit <= bound {
+                *bucket += 1;
+            }
+        }
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.bytes.iter().map(|bytes| bytes.load(Ordering::Relaxed) as usize).sum()
+    }
+
+    pub fn total_stream_duration(&self) -> Duration {
+        *self.stream_duration.lock().unwrap()
+    }
+
+    pub fn render(&self, running_sessions: usize, queued_sessions: usize) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP speedtest_bytes_total Payload bytes transferred by the server.\n# TYPE speedtest_bytes_total counter\n");
+        for (direction, bytes) in ["upload", "download", "bidirectional"].iter().zip(&self.bytes) {
+            let _ = writeln!(out, "speedtest_bytes_total{{direction=\"{}\"}} {}", direction, bytes.load(Ordering::Relaxed));
+        }
+
+        out.push_str("# HELP speedtest_streams_total Streams that finished a test.\n# TYPE speedtest_streams_total counter\n");
+        let _ = writeln!(out, "speedtest_streams_total {}", self.streams.load(Ordering::Relaxed));
+        out.push_str("# HELP speedtest_stream_seconds_total Summed duration of finished streams.\n# TYPE speedtest_stream_seconds_total counter\n");
+        let _ = writeln!(out, "speedtest_stream_seconds_total {}", self.total_stream_duration().as_secs_f64());
+        out.push_str("# HELP speedtest_active_streams Streams currently transferring.\n# TYPE speedtest_active_streams gauge\n");
+        let _ = writeln!(out, "speedtest_active_streams {}", self.active_streams.load(Ordering::Relaxed));
+
+        out.push_str("# HELP speedtest_active_sessions Sessions currently running.\n# TYPE speedtest_active_sessions gauge\n");
+        let _ = writeln!(out, "speedtest_active_sessions {}", running_sessions);
+        out.push_str("# HELP speedtest_queued_sessions Sessions waiting in the exclusive mode queue.\n# TYPE speedtest_queued_sessions gauge\n");
+        let _ = writeln!(out, "speedtest_queued_sessions {}", queued_sessions);
+
+        out.push_str("# HELP speedtest_rejected_total Connections turned away, by reason.\n# TYPE speedtest_rejected_total counter\n");
+        for (reason, count) in Rejection::ALL.iter().zip(&self.rejected) {
+            let _ = writeln!(out, "speedtest_rejected_total{{reason=\"{}\"}} {}", reason.label(), count.load(Ordering::Relaxed));
+        }
+
+        let sessions = self.sessions.lock().unwrap();
+        out.push_str("# HELP speedtest_sessions_total Finished sessions, by result.\n# TYPE speedtest_sessions_total counter\n");
+        let _ = writeln!(out, "speedtest_sessions_total{{result=\"succeeded\"}} {}", sessions.succeeded);
+        let _ = writeln!(out, "speedtest_sessions_total{{result=\"failed\"}} {}", sessions.failed);
+
+        let count = sessions.succeeded + sessions.failed;
+        out.push_str("# HELP speedtest_session_throughput_mbit Throughput of finished sessions in MBit/s.\n# TYPE speedtest_session_throughput_mbit histogram\n");
+        for (bound, bucket) in THROUGHPUT_BUCKETS.iter().zip(sessions.buckets) {
+            let _ = writeln!(out, "speedtest_session_throughput_mbit_bucket{{le=\"{}\"}} {}", bound, bucket);
+        }
+        let _ = writeln!(out, "speedtest_session_throughput_mbit_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(out, "speedtest_session_throughput_mbit_sum {}", sessions.mbit_sum);
+        let _ = writeln!(out, "speedtest_session_throughput_mbit_count {}", count);
+
+        out
+    }
+}
+
+/// Feeds the summaries of ended sessions into the metrics.
+/// With `verbose` summaries that were missed are logged.
+pub async fn record_sessions(mut completed: broadcast::Receiver<SessionSummary>, metrics: Arc<Metrics>, verbose: bool) {
+    loop {
+        match completed.recv().await {
+            Ok(summary) => metrics.session_finished(&summary),
+            Err(broadcast::error::RecvError::Lagged(missed)) if verbose => eprintln!("Metrics missed {} session summaries", missed),
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Answers `GET /metrics` over plain HTTP/1.1, everything else with 404. With `verbose` failed
+/// requests are logged.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, sessions: Sessions, verbose: bool) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(socket, &metrics, &sessions).await
+                && verbose
+            {
+                eprintln!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer(mut socket: TcpStream, metrics: &Metrics, sessions: &Sessions) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Incomplete HTTP request"));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&head).lines().next().unwrap_or_default().to_string();
+    let (status, body) = match request_line.split(' ').take(2).collect::<Vec<_>>().as_slice() {
+        ["GET", "/metrics"] => {
+            let (running, queued) = sessions.counts();
+            ("200 OK", metrics.render(running, queued))
+        }
+        _ => ("404 Not Found", "Not found, try /metrics\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.stream_started();
+        metrics.stream_finished(Direction::Download, 2_000, Duration::from_secs(1));
+        metrics.rejected(Rejection::Auth);
+        metrics.session_finished(&SessionSummary {
+            id: "a".to_string(),
+            direction: Direction::Download,
+            announced: 1,
+            joined: 1,
+            failed: 0,
+            bytes: 12_500_000,
+            duration: Duration::from_secs(1),
+        });
+
+        let text = metrics.render(0, 2);
+        assert!(text.contains("speedtest_bytes_total{direction=\"download\"} 2000\n"));
+        assert!(text.contains("speedtest_active_streams 0\n"));
+        assert!(text.contains("speedtest_queued_sessions 2\n"));
+        assert!(text.contains("speedtest_rejected_total{reason=\"auth\"} 1\n"));
+        assert!(text.contains("speedtest_session_throughput_mbit_bucket{le=\"50\"} 0\n"));
+        assert!(text.contains("speedtest_session_throughput_mbit_bucket{le=\"100\"} 1\n"));
+        assert!(text.contains("speedtest_session_throughput_mbit_count 1\n"));
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::Local;
+use clap::ValueEnum;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::path::PathBuf;
+use tokio::time::Duration;
+
+use speedtest::{
+    Direction, client,
+    net::ConnectOptions,
+    sink::{Measurement, Sink},
+    utils::{generate_test_sizes, print_statistics_terminal},
+};
+
+use crate::{
+    alert::{AlertOptions, Alerts},
+    file::{read_test_file, write_test_file},
+    schedule::Schedule,
+    stop::{pause, stop_after_test},
+};
+
+/// One test of a monitor cycle.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum TestKind {
+    Download,
+    Upload,
+    Bidirectional,
+    /// Write and read files of growing size at `--path`.
+    File,
+}
+
+pub struct MonitorOptions {
+    pub address: String,
+    pub connect: ConnectOptions,
+    pub threads: usize,
+    pub block_size_kb: usize,
+    pub duration_secs: u64,
+    pub tests: Vec<TestKind>,
+    pub schedule: Schedule,
+    /// Stop after this many cycles.
+    pub iterations: Option<u64>,
+    /// Random delay of up to this much added to every cycle start.
+    pub jitter: Duration,
+    /// Pause between two tests of a cycle.
+    pub pause: Duration,
+    pub path: PathBuf,
+    pub file_size_mb: usize,
+    pub sinks: Vec<Sink>,
+    pub alerts: AlertOptions,
+}
+
+/// Runs test cycles on the schedule until the iterations are done or a signal arrives.
+/// The first signal lets the running test finish, a second one exits immediately.
+pub async fn run_monitor(options: MonitorOptions) {
+    let mut alerts = Alerts::new(options.alerts.clone());
+
+    let mut stop = stop_after_test();
+
+    let mut previous = None;
+    let mut cycle = 0;
+    while options.iterations.is_none_or(|iterations| cycle < iterations) {
+        let start = options.schedule.next(previous, Local::now());
+        let delay = (start - Local::now()).to_std().unwrap_or_default() + random_delay(options.jitter);
+        if !delay.is_zero() {
+            println!("Next cycle at {}", (Local::now() + delay).format("%Y-%m-%d %H:%M:%S"));
+        }
+        if !pause(delay, &mut stop).await {
+            break;
+        }
+
+        cycle += 1;
+        previous = Some(start);
+        println!("\n[ZYKLUS {}]", cycle);
+
+        for (index, test) in options.tests.iter().enumerate() {
+            if index > 0 && !pause(options.pause, &mut stop).await {
+                break;
+            }
+            for measurement in run_test(*test, &options).await {
+                for sink in &options.sinks {
+                    if let Err(e) = sink.write(&measurement) {
+                        eprintln!("Failed to write result to {:?}: {}", sink, e);
+                    }
+                }
+
+                if *test != TestKind::File {
+                    let violations = alerts.check(&measurement);
+                    if !violations.is_empty() {
+                        alerts.raise(&measurement, &violations).await;
+                    }
+                }
+            }
+        }
+
+        if *stop.borrow() {
+            break;
+        }
+    }
+
+    println!("Monitor stopped after {} cycles", cycle);
+}
+
+async fn run_test(test: TestKind, options: &MonitorOptions) -> Vec<Measurement> {
+    let direction = match test {
+        TestKind::Download => Direction::Download,
+        TestKind::Upload => Direction::Upload,
+        TestKind::Bidirectional => Direction::Bidirectional,
+        TestKind::File => return run_file_test(options).await,
+    };
+
+    let measurement = client::run_client(
+        options.address.clone(),
+        options.connect.clone(),
+        options.threads,
+        options.block_size_kb,
+        options.duration_secs,
+        direction,
+        false,
+    )
+    .await;
+
+    // A test that could not run is recorded as one where every stream failed
+    vec![measurement.unwrap_or_else(|| Measurement {
+        timestamp: Local::now(),
+        target: options.address.clone(),
+        direction,
+        block_size_kb: options.block_size_kb,
+        duration_secs: options.duration_secs as f64,
+        bytes: 0,
+        latency_ms: None,
+        errors: options.threads,
+        stream_bytes: Vec::new(),
+    })]
+}
+
+/// Writes and reads files of growing size, writing counts as upload and reading as download.
+async fn run_file_test(options: &MonitorOptions) -> Vec<Measurement> {
+    let mut measurements = Vec::new();
+    let target = options.path.display().to_string();
+
+    for size in generate_test_sizes(options.file_size_mb * 1024 * 1024) {
+        for direction in [Direction::Upload, Direction::Download] {
+            let duration = match direction {
+                Direction::Upload => write_test_file(&options.path, size).await,
+                _ => read_test_file(&options.path).await,
+            };
+            let duration = match duration {
+                Ok(duration) => duration.as_secs_f64(),
+                Err(e) => {
+                    eprintln!("File test with {} failed: {}", target, e);
+                    return measurements;
+                }
+            };
+
+            println!("\n[ERGEBNIS] Datei {:?} {} Bytes", direction, size);
+            print_statistics_terminal(duration, size);
+            measurements.push(Measurement {
+                timestamp: Local::now(),
+                target: target.clone(),
+                direction,
+                block_size_kb: 0,
+                duration_secs: duration,
+                bytes: size,
+                latency_ms: None,
+                errors: 0,
+                stream_bytes: Vec::new(),
+            });
+        }
+    }
+
+    measurements
+}
+
+fn random_delay(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let mut bytes = [0u8; 8];
+    SystemRandom::new().fill(&mut bytes).expect("No randomness available");
+    Duration::from_millis(u64::from_le_bytes(bytes) % (max.as_millis() as u64 + 1))
+}
@@ -0,0 +1,515 @@
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, lookup_host};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::{Duration, Instant};
+
+use crate::auth::Key;
+use crate::live::SocketProbe;
+use crate::quic::{self, QuicClientOptions};
+use crate::tls::{self, TlsClientOptions, TlsInfo};
+use crate::transport::{Acceptor, BoxFuture, Connector};
+
+/// Prefix selecting a Unix domain socket instead of TCP, e.g. `unix:/tmp/speedtest.sock`.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Any byte stream a test can run over (TCP, Unix domain socket, ...).
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxStream = Box<dyn AsyncStream>;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn from_flags(ipv4: bool, ipv6: bool) -> Self {
+        match (ipv4, ipv6) {
+            (true, false) => AddressFamily::V4,
+            (false, true) => AddressFamily::V6,
+            _ => AddressFamily::Any,
+        }
+    }
+
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Local address given via `--bind`, either `IP` or `IP:PORT`. Port 0 means an ephemeral port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BindAddress(pub SocketAddr);
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(BindAddress(addr));
+        }
+
+        s.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map(|ip| BindAddress(SocketAddr::new(ip, 0)))
+            .map_err(|_| format!("Invalid bind address '{}', expected IP or IP:PORT", s))
+    }
+}
+
+/// Address the server listens on: an IP address (see [`BindAddress`]) or `unix:/path`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ListenAddress {
+    Ip(BindAddress),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            None => s.parse().map(ListenAddress::Ip),
+        }
+    }
+}
+
+/// How the client opens its stream sockets. The default is plain TCP without a key.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    pub family: AddressFamily,
+    pub bind: Option<BindAddress>,
+    pub interface: Option<String>,
+    pub tls: Option<TlsClientOptions>,
+    pub quic: Option<QuicClientOptions>,
+    pub psk: Option<Key>,
+    pub busy: BusyPolicy,
+    /// Sample `TCP_INFO` of every stream for the live view.
+    pub probe: bool,
+}
+
+/// What the client does while the server runs another session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BusyPolicy {
+    #[default]
+    Fail,
+    Retry(u32),
+    Wait,
+}
+
+impl ConnectOptions {
+    /// Local address for the n-th stream (0-based). A fixed source port is counted up per stream,
+    /// since parallel streams to the same server cannot share one. Counting past 65535 is an error.
+    fn local_addr(&self, stream_index: usize) -> io::Result<Option<SocketAddr>> {
+        let Some(BindAddress(addr)) = self.bind else {
+            return Ok(None);
+        };
+        if addr.port() == 0 {
+            return Ok(Some(addr));
+        }
+        u16::try_from(stream_index)
+            .ok()
+            .and_then(|index| addr.port().checked_add(index))
+            .map(|port| Some(SocketAddr::new(addr.ip(), port)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Local port {} + stream {} is beyond 65535, use a lower port in --bind", addr.port(), stream_index),
+                )
+            })
+    }
+}
+
+pub fn family_name(addr: &SocketAddr) -> &'static str {
+    match addr {
+        SocketAddr::V4(_) => "IPv4",
+        SocketAddr::V6(v6) if v6.ip().to_ipv4_mapped().is_some() => "IPv4 (mapped)",
+        SocketAddr::V6(_) => "IPv6",
+    }
+}
+
+/// Resolved server address of the client. `host` is kept for TLS server name indication.
+/// With QUIC the connection is established once and every stream is opened inside it.
+pub enum Target {
+    Tcp { host: String, addrs: Vec<SocketAddr> },
+    Unix(PathBuf),
+    Quic(quic::ClientConnection),
+}
+
+impl Target {
+    /// Ends the shared QUIC connection after all streams are done.
+    pub async fn close(&self) {
+        if let Target::Quic(connection) = self {
+            connection.close().await;
+        }
+    }
+}
+
+/// Streams over TCP or a Unix socket, with TLS on top if configured, or inside one QUIC connection.
+pub struct NetConnector {
+    pub target: Target,
+    pub options: ConnectOptions,
+}
+
+impl NetConnector {
+    /// Resolves `address` and, with QUIC, establishes the connection the streams share.
+    pub async fn resolve(address: &str, options: ConnectOptions) -> io::Result<NetConnector> {
+        let mut target = resolve(address, options.family)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to resolve {}: {}", address, e)))?;
+        if let Some(quic_options) = &options.quic {
+            target = quic::connect(target, quic_options, &options)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to connect via QUIC: {}", e)))?;
+        }
+        Ok(NetConnector { target, options })
+    }
+}
+
+impl Connector for NetConnector {
+    fn connect(&self, stream_index: usize) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(connect(&self.target, &self.options, stream_index))
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.target.close())
+    }
+}
+
+/// One connected client stream.
+pub struct Connection {
+    pub stream: BoxStream,
+    pub description: String,
+    pub tls: Option<TlsInfo>,
+    /// Round trip to the server: the TCP or Unix socket connect, or the smoothed QUIC round trip time.
+    pub latency: Duration,
+    /// For `TCP_INFO` in the live view, TCP only.
+    pub probe: Option<SocketProbe>,
+}
+
+/// Resolves `address` (host:port or `unix:/path`). For TCP every result of the requested family is kept.
+pub async fn resolve(address: &str, family: AddressFamily) -> io::Result<Target> {
+    if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+        return Ok(Target::Unix(PathBuf::from(path)));
+    }
+
+    let addrs: Vec<SocketAddr> = lookup_host(address).await?.filter(|addr| family.matches(addr)).collect();
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No {:?} address found for '{}'", family, address)));
+    }
+
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host).to_string();
+    Ok(Target::Tcp { host, addrs })
+}
+
+/// Opens one stream to `target`, running the TLS handshake on top if configured.
+pub async fn connect(target: &Target, options: &ConnectOptions, stream_index: usize) -> io::Result<Connection> {
+    let start = Instant::now();
+    let (stream, description, host, probe): (BoxStream, String, &str, _) = match target {
+        Target::Tcp { host, addrs } => {
+            let stream = connect_any(addrs, options, stream_index).await?;
+            let (local, peer) = (stream.local_addr()?, stream.peer_addr()?);
+            let probe = options.probe.then(|| SocketProbe::new(&stream)).flatten();
+            (Box::new(stream), format!("{}: {} -> {}", family_name(&peer), local, peer), host, probe)
+        }
+        Target::Unix(path) => (connect_unix(path).await?, format!("Unix socket {}", path.display()), "localhost", None),
+        Target::Quic(connection) => {
+            let (stream, description) = connection.open_stream().await?;
+            let latency = connection.rtt();
+            return Ok(Connection {
+                stream,
+                description,
+                tls: None,
+                latency,
+                probe: None,
+            });
+        }
+    };
+    let latency = start.elapsed();
+
+    match &options.tls {
+        Some(tls_options) => {
+            let (stream, info) = tls::connect(stream, tls_options, host).await?;
+            Ok(Connection {
+                stream,
+                description,
+                tls: Some(info),
+                latency,
+                probe,
+            })
+        }
+        None => Ok(Connection {
+            stream,
+            description,
+            tls: None,
+            latency,
+            probe,
+        }),
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &std::path::Path) -> io::Result<BoxStream> {
+    Ok(Box::new(UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &std::path::Path) -> io::Result<BoxStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unix domain sockets are not supported on this platform ({})", path.display()),
+    ))
+}
+
+/// Connects to the first reachable address of `addrs`, in resolver order.
+/// Addresses of a different family than the bind address are skipped.
+async fn connect_any(addrs: &[SocketAddr], options: &ConnectOptions, stream_index: usize) -> io::Result<TcpStream> {
+    let local = options.local_addr(stream_index)?;
+    let mut last_err = None;
+
+    for addr in addrs.iter().filter(|addr| local.is_none_or(|local| local.is_ipv4() == addr.is_ipv4())) {
+        match connect_from(*addr, local, options.interface.as_deref()).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address matches the family of the bind address")))
+}
+
+async fn connect_from(addr: SocketAddr, local: Option<SocketAddr>, interface: Option<&str>) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+
+    if let Some(local) = local {
+        socket.set_reuseaddr(true)?;
+        socket.bind(local)?;
+    }
+
+    socket.connect(addr).await
+}
+
+/// Server socket, either TCP or a Unix domain socket.
+pub enum Listener {
+    /// With `probe` accepted streams are sampled for the live view.
+    Tcp { listener: TcpListener, probe: bool },
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Remote end of an accepted connection.
+pub struct Peer {
+    pub addr: String,
+    pub transport: &'static str,
+    pub probe: Option<SocketProbe>,
+}
+
+impl Acceptor for Listener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, Peer)>> {
+        Box::pin(async move {
+            match self {
+                Listener::Tcp { listener, probe } => {
+                    let (stream, addr) = listener.accept().await?;
+                    let peer = Peer {
+                        addr: addr.to_string(),
+                        transport: family_name(&addr),
+                        probe: probe.then(|| SocketProbe::new(&stream)).flatten(),
+                    };
+                    Ok((Box::new(stream) as BoxStream, peer))
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener, path) => {
+                    let (stream, _) = listener.accept().await?;
+                    // Unix clients are unnamed, so label them with the local fd instead
+                    let peer = Peer {
+                        addr: format!("{}#{}", path.display(), std::os::fd::AsRawFd::as_raw_fd(&stream)),
+                        transport: "Unix",
+                        probe: None,
+                    };
+                    Ok((Box::new(stream) as BoxStream, peer))
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp { listener, .. } => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp { listener, .. } => match listener.local_addr() {
+                Ok(addr) if addr.ip().is_unspecified() && addr.is_ipv6() => format!("{} (dual-stack)", addr),
+                Ok(addr) => format!("{} ({})", addr, family_name(&addr)),
+                Err(_) => "unknown address".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("{}{} (Unix)", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Binds the server socket. Without an explicit address the server listens dual-stack on `[::]`
+/// and falls back to `0.0.0.0` on hosts without IPv6. A port in `bind` overrides `port`.
+/// With `probe` accepted TCP streams are sampled for the live view.
+pub fn bind_listener(bind: Option<&ListenAddress>, port: u16, interface: Option<&str>, probe: bool) -> io::Result<Listener> {
+    match bind {
+        Some(ListenAddress::Unix(path)) => listen_unix(path.clone()),
+        _ => {
+            let socket = bind_socket(bind, port, Type::STREAM, interface)?;
+            socket.listen(1024)?;
+            Ok(Listener::Tcp {
+                listener: TcpListener::from_std(socket.into())?,
+                probe,
+            })
+        }
+    }
+}
+
+/// Binds the UDP socket for QUIC with the same address rules as [`bind_listener`].
+pub fn bind_udp(bind: Option<&ListenAddress>, port: u16, interface: Option<&str>) -> io::Result<std::net::UdpSocket> {
+    match bind {
+        Some(ListenAddress::Unix(path)) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("QUIC cannot listen on a Unix socket ({})", path.display()))),
+        _ => bind_socket(bind, port, Type::DGRAM, interface).map(Into::into),
+    }
+}
+
+/// Opens a UDP socket on `addr`, e.g. for the client side of QUIC.
+pub fn open_udp(addr: SocketAddr, interface: Option<&str>) -> io::Result<std::net::UdpSocket> {
+    open_socket(addr, Type::DGRAM, interface).map(Into::into)
+}
+
+fn bind_socket(bind: Option<&ListenAddress>, port: u16, ty: Type, interface: Option<&str>) -> io::Result<Socket> {
+    match bind {
+        Some(ListenAddress::Ip(BindAddress(addr))) => open_socket(SocketAddr::new(addr.ip(), if addr.port() == 0 { port } else { addr.port() }), ty, interface),
+        _ => open_socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port), ty, interface).or_else(|_| open_socket(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port), ty, interface)),
+    }
+}
+
+#[cfg(unix)]
+fn listen_unix(path: PathBuf) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket file left over from an earlier run would make bind fail; never remove anything else,
+    // and never the socket of a server that still answers
+    if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("Another server is listening on {}", path.display())));
+        }
+        std::fs::remove_file(&path)?;
+    }
+
+    Ok(Listener::Unix(UnixListener::bind(&path)?, path))
+}
+
+#[cfg(not(unix))]
+fn listen_unix(path: PathBuf) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unix domain sockets are not supported on this platform ({})", path.display()),
+    ))
+}
+
+fn open_socket(addr: SocketAddr, ty: Type, interface: Option<&str>) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    socket.set_reuse_address(true)?;
+
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+
+    // An unspecified IPv6 address accepts IPv4 clients as well (dual-stack)
+    if let SocketAddr::V6(v6) = addr {
+        socket.set_only_v6(!v6.ip().is_unspecified())?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+/// Restricts a socket to one network interface (`SO_BINDTODEVICE`).
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device<S: std::os::fd::AsFd>(socket: &S, interface: &str) -> io::Result<()> {
+    socket2::SockRef::from(socket).bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device<S>(_socket: &S, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Binding to interface '{}' is only supported on Linux", interface)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind_address() {
+        assert_eq!("10.0.0.1".parse::<BindAddress>().unwrap().0, "10.0.0.1:0".parse().unwrap());
+        assert_eq!("10.0.0.1:5000".parse::<BindAddress>().unwrap().0, "10.0.0.1:5000".parse().unwrap());
+        assert_eq!("[fe80::1]".parse::<BindAddress>().unwrap().0, "[fe80::1]:0".parse().unwrap());
+        assert_eq!("[fe80::1]:5000".parse::<BindAddress>().unwrap().0, "[fe80::1]:5000".parse().unwrap());
+        assert!("eth0".parse::<BindAddress>().is_err());
+        assert_eq!("unix:/tmp/st.sock".parse::<ListenAddress>().unwrap(), ListenAddress::Unix(PathBuf::from("/tmp/st.sock")));
+    }
+
+    #[test]
+    fn test_local_port_per_stream() {
+        let options = |bind: &str| ConnectOptions {
+            bind: Some(bind.parse().unwrap()),
+            ..ConnectOptions::default()
+        };
+        assert_eq!(options("10.0.0.1").local_addr(3).unwrap(), Some("10.0.0.1:0".parse().unwrap()));
+        assert_eq!(options("10.0.0.1:5000").local_addr(3).unwrap(), Some("10.0.0.1:5003".parse().unwrap()));
+        assert_eq!(options("10.0.0.1:65534").local_addr(1).unwrap(), Some("10.0.0.1:65535".parse().unwrap()));
+        assert!(options("10.0.0.1:65534").local_addr(2).is_err(), "Ports must not wrap around");
+        assert_eq!(ConnectOptions::default().local_addr(3).unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_in_use() {
+        let path = std::env::temp_dir().join(format!("speedtest-net-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = listen_unix(path.clone()).unwrap();
+        let error = listen_unix(path.clone()).err().expect("A live socket must not be taken over");
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        // The socket file of a stopped server is replaced
+        drop(listen_unix(path.clone()).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+}
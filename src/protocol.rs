@@ -0,0 +1,321 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use clap::ValueEnum;
+
+use crate::Direction;
+use crate::auth::{self, Key};
+
+/// Longest handshake line accepted, guards against clients sending garbage without newline.
+const MAX_LINE: usize = 1024;
+
+/// Server answer to a request line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Ok,
+    Challenge(String),
+    /// The server runs another session; `position` in its queue and estimated wait in seconds.
+    Busy {
+        position: usize,
+        wait_secs: u64,
+    },
+    Error(String),
+}
+
+impl Reply {
+    pub fn to_line(&self) -> String {
+        match self {
+            Reply::Ok => "ok\n".to_string(),
+            Reply::Challenge(nonce) => format!("challenge {}\n", nonce),
+            Reply::Busy { position, wait_secs } => format!("busy {} {}\n", position, wait_secs),
+            Reply::Error(reason) => format!("error {}\n", reason),
+        }
+    }
+
+    pub fn parse(line: &str) -> Reply {
+        match line.split_once(' ') {
+            _ if line == "ok" => Reply::Ok,
+            Some(("challenge", nonce)) => Reply::Challenge(nonce.to_string()),
+            Some(("busy", rest)) => match rest.split_once(' ').map(|(position, wait)| (position.parse(), wait.parse())) {
+                Some((Ok(position), Ok(wait_secs))) => Reply::Busy { position, wait_secs },
+                _ => Reply::Error(format!("unexpected reply '{}'", line)),
+            },
+            Some(("error", reason)) => Reply::Error(reason.to_string()),
+            _ => Reply::Error(format!("unexpected reply '{}'", line)),
+        }
+    }
+}
+
+/// What a client asks the server to do, sent as one line per field:
+/// `<mode>\n<duration>\nsession <id> <streams>[ wait]\n`, or just `quit\n`.
+/// An agent is told to run a test itself with `run <mode>\n<duration>\ntarget <address> <streams> <block size KB> <transport>\n`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub direction: Direction,
+    pub duration_secs: u64,
+    /// Identifies the streams of one client run, chosen randomly by the client.
+    pub session: String,
+    pub streams: usize,
+    /// Keep the connection open in the queue while the server is busy.
+    pub wait: bool,
+    /// Set for administrative run commands to an agent. The agent's session holds the controller's id.
+    pub run: Option<RunRequest>,
+}
+
+/// Test an agent runs against another server on behalf of a controller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunRequest {
+    /// Server address as seen from the agent.
+    pub target: String,
+    pub block_size_kb: usize,
+    pub transport: Transport,
+}
+
+/// How an agent connects to the target of a run command.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Quic,
+}
+
+impl Request {
+    pub fn to_lines(&self) -> String {
+        if let Some(run) = &self.run {
+            return format!(
+                "run {}\n{}\ntarget {} {} {} {}\n",
+                mode_name(self.direction),
+                self.duration_secs,
+                run.target,
+                self.streams,
+                run.block_size_kb,
+                transport_name(run.transport)
+            );
+        }
+
+        match self.direction {
+            Direction::Quit => "quit\n".to_string(),
+            direction => format!(
+                "{}\n{}\nsession {} {}{}\n",
+                mode_name(direction),
+                self.duration_secs,
+                self.session,
+                self.streams,
+                if self.wait { " wait" } else { "" }
+            ),
+        }
+    }
+}
+
+fn mode_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Upload => "upload",
+        Direction::Download => "download",
+        Direction::Bidirectional => "bidirectional",
+        Direction::Quit => "quit",
+    }
+}
+
+fn transport_name(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Tcp => "tcp",
+        Transport::Tls => "tls",
+        Transport::Quic => "quic",
+    }
+}
+
+pub fn new_session_id() -> io::Result<String> {
+    auth::random_hex(8)
+}
+
+/// Reads a request and returns it with its exact text, which the authentication signs.
+/// On error, returns the reason for the client.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Request, String), String> {
+    let mode = read_field(stream, "mode").await?;
+    let (run, test_mode) = match mode.split_once(' ') {
+        Some(("run", test_mode)) => (true, test_mode),
+        _ => (false, mode.as_str()),
+    };
+    let direction = match test_mode {
+        "upload" => Direction::Upload,
+        "download" => Direction::Download,
+        "bidirectional" => Direction::Bidirectional,
+        "quit" => Direction::Quit,
+        _ => return Err(format!("unknown direction '{}'", mode)),
+    };
+    if direction == Direction::Quit {
+        if run {
+            return Err("an agent cannot run quit".to_string());
+        }
+        let request = Request {
+            direction,
+            duration_secs: 0,
+            session: String::new(),
+            streams: 0,
+            wait: false,
+            run: None,
+        };
+        return Ok((request, "quit\n".to_string()));
+    }
+
+    let duration = read_field(stream, "duration").await?;
+    let duration_secs = duration.parse::<u64>().map_err(|_| format!("invalid duration '{}'", duration))?;
+
+    if run {
+        let target_line = read_field(stream, "target").await?;
+        let fields: Vec<&str> = target_line.split(' ').collect();
+        let (target, streams, block_size_kb, transport) = match fields.as_slice() {
+            ["target", target, streams, block_size_kb, transport] => (target, streams, block_size_kb, transport),
+            _ => return Err(format!("invalid target line '{}'", target_line)),
+        };
+        let request = Request {
+            direction,
+            duration_secs,
+            session: String::new(),
+            streams: streams.parse().map_err(|_| format!("invalid stream count '{}'", streams))?,
+            wait: false,
+            run: Some(RunRequest {
+                target: target.to_string(),
+                block_size_kb: block_size_kb.parse().map_err(|_| format!("invalid block size '{}'", block_size_kb))?,
+                transport: Transport::from_str(transport, false).map_err(|_| format!("unknown transport '{}'", transport))?,
+            }),
+        };
+        return Ok((request, format!("{}\n{}\n{}\n", mode, duration, target_line)));
+    }
+
+    let session_line = read_field(stream, "session").await?;
+    let fields: Vec<&str> = session_line.split(' ').collect();
+    let (session, streams, wait) = match fields.as_slice() {
+        ["session", id, streams, flags @ ..] if flags.iter().all(|flag| *flag == "wait") => {
+            (id.to_string(), streams.parse::<usize>().map_err(|_| format!("invalid stream count '{}'", streams))?, !flags.is_empty())
+        }
+        _ => return Err(format!("invalid session line '{}'", session_line)),
+    };
+
+    let text = format!("{}\n{}\n{}\n", mode, duration, session_line);
+    Ok((
+        Request {
+            direction,
+            duration_secs,
+            session,
+            streams,
+            wait,
+            run: None,
+        },
+        text,
+    ))
+}
+
+async fn read_field<S: AsyncRead + Unpin>(stream: &mut S, name: &str) -> Result<String, String> {
+    match read_line(stream).await {
+        Ok(line) if !line.is_empty() => Ok(line),
+        Ok(_) => Err(format!("missing {} line", name)),
+        Err(e) => Err(format!("failed to read {} line: {}", name, e)),
+    }
+}
+
+/// Reads one `\n` terminated line byte by byte, so no payload after the handshake is consumed.
+/// Returns the trimmed line, or an empty string if the peer closed the stream.
+pub async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+
+    loop {
+        if stream.read(&mut byte).await? == 0 || byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Handshake line too long"));
+        }
+        line.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&line).trim().to_string())
+}
+
+pub async fn send_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: &Reply) -> io::Result<()> {
+    stream.write_all(reply.to_line().as_bytes()).await
+}
+
+/// Outcome of a handshake the server did not reject.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Handshake {
+    Accepted,
+    Busy { position: usize, wait_secs: u64 },
+}
+
+/// Client side of the handshake: sends `request` and waits for `ok`, answering a challenge with `key`.
+pub async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, request: &str, key: Option<&Key>) -> io::Result<Handshake> {
+    stream.write_all(request.as_bytes()).await?;
+    next_reply(stream, request, key).await
+}
+
+/// Waits for the server's next answer, e.g. after a `busy` reply to a request that waits in the queue.
+pub async fn next_reply<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, request: &str, key: Option<&Key>) -> io::Result<Handshake> {
+    loop {
+        let line = read_line(stream).await?;
+        if line.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection during the handshake"));
+        }
+
+        match Reply::parse(&line) {
+            Reply::Ok => return Ok(Handshake::Accepted),
+            Reply::Busy { position, wait_secs } => return Ok(Handshake::Busy { position, wait_secs }),
+            Reply::Challenge(nonce) => {
+                let key = key.ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "Server requires a pre-shared key (--psk)"))?;
+                let response = auth::respond(key, &nonce, request);
+                stream.write_all(format!("response {}\n", response).as_bytes()).await?;
+            }
+            Reply::Error(reason) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Server rejected the test: {}", reason))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_roundtrip() {
+        for reply in [
+            Reply::Ok,
+            Reply::Challenge("00ff".to_string()),
+            Reply::Busy { position: 2, wait_secs: 14 },
+            Reply::Error("authentication failed".to_string()),
+        ] {
+            assert_eq!(Reply::parse(reply.to_line().trim()), reply);
+        }
+        assert!(matches!(Reply::parse("garbage"), Reply::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_request_roundtrip() {
+        let request = Request {
+            direction: Direction::Download,
+            duration_secs: 10,
+            session: "0011aabb".to_string(),
+            streams: 4,
+            wait: true,
+            run: None,
+        };
+        let run = Request {
+            session: String::new(),
+            wait: false,
+            run: Some(RunRequest {
+                target: "10.0.0.2:4000".to_string(),
+                block_size_kb: 128,
+                transport: Transport::Quic,
+            }),
+            ..request.clone()
+        };
+
+        for request in [request, run] {
+            let lines = request.to_lines();
+            let (parsed, text) = read_request(&mut lines.as_bytes()).await.unwrap();
+            assert_eq!(parsed, request);
+            assert_eq!(text, lines);
+        }
+        assert!(read_request(&mut "download\nten\n".as_bytes()).await.is_err());
+        assert!(read_request(&mut "run quit\n".as_bytes()).await.is_err());
+    }
+}
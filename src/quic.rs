@@ -0,0 +1,143 @@
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{ClientConfig, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, TransportConfig, VarInt};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+
+use crate::net::{BoxStream, ConnectOptions, ListenAddress, Target, bind_udp, family_name, open_udp};
+use crate::tls::{self, Identity, Verification, invalid_input};
+
+/// ALPN protocol id, required by QUIC.
+const ALPN: &[u8] = b"speedtest";
+
+/// Time the server gets to acknowledge the last data of a test before the client closes anyway.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound for parallel streams (`threads`) in one QUIC connection.
+const MAX_STREAMS: u32 = 1024;
+
+/// Client side QUIC settings.
+#[derive(Clone, Debug)]
+pub struct QuicClientOptions {
+    pub config: ClientConfig,
+    pub server_name: Option<String>,
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS));
+    transport.keep_alive_interval(Some(Duration::from_secs(5)));
+    Arc::new(transport)
+}
+
+pub fn client_config(verification: &Verification, server_name: Option<String>) -> io::Result<QuicClientOptions> {
+    let mut crypto = tls::build_client_config(verification)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).map_err(invalid_input)?));
+    config.transport_config(transport_config());
+
+    Ok(QuicClientOptions { config, server_name })
+}
+
+pub fn server_config(identity: &Identity) -> io::Result<ServerConfig> {
+    let mut crypto = tls::build_server_config(identity)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto).map_err(invalid_input)?));
+    config.transport_config(transport_config());
+
+    Ok(config)
+}
+
+/// Opens the UDP endpoint of the server next to its TCP listener.
+pub fn bind_server(bind: Option<&ListenAddress>, port: u16, interface: Option<&str>, config: ServerConfig) -> io::Result<Endpoint> {
+    let socket = bind_udp(bind, port, interface)?;
+    Endpoint::new(EndpointConfig::default(), Some(config), socket, Arc::new(TokioRuntime))
+}
+
+/// Establishes the QUIC connection all streams of a test share. Resolved addresses are tried in order.
+pub async fn connect(target: Target, quic: &QuicClientOptions, options: &ConnectOptions) -> io::Result<Target> {
+    let Target::Tcp { host, addrs } = target else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "QUIC requires a HOST:PORT address"));
+    };
+    let server_name = tls::server_name(quic.server_name.as_deref(), &host)?.to_str().into_owned();
+
+    let mut last_err = None;
+    for addr in addrs {
+        let local = match options.bind {
+            Some(bind) => bind.0,
+            None if addr.is_ipv4() => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            None => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        if local.is_ipv4() != addr.is_ipv4() {
+            continue;
+        }
+
+        let mut endpoint = Endpoint::new(EndpointConfig::default(), None, open_udp(local, options.interface.as_deref())?, Arc::new(TokioRuntime))?;
+        endpoint.set_default_client_config(quic.config.clone());
+
+        let start = Instant::now();
+        let connecting = endpoint.connect(addr, &server_name).map_err(invalid_input)?;
+        match connecting.await {
+            Ok(connection) => {
+                return Ok(Target::Quic(ClientConnection {
+                    description: format!("{}: {} -> {}", family_name(&addr), endpoint.local_addr()?, addr),
+                    handshake: start.elapsed(),
+                    endpoint,
+                    connection,
+                    delivering: Mutex::new(Vec::new()),
+                }));
+            }
+            Err(e) => last_err = Some(io::Error::new(io::ErrorKind::ConnectionRefused, e)),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address matches the family of the bind address")))
+}
+
+/// The connection all streams of a client test share.
+pub struct ClientConnection {
+    /// Family and the local and remote address.
+    pub description: String,
+    pub handshake: Duration,
+    endpoint: Endpoint,
+    connection: quinn::Connection,
+    /// Per stream, waits until the server acknowledged all data sent on it.
+    delivering: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ClientConnection {
+    /// Opens one bidirectional stream.
+    pub async fn open_stream(&self) -> io::Result<(BoxStream, String)> {
+        let (send, recv) = self.connection.open_bi().await?;
+        let description = format!("QUIC stream {} to {}", send.id().index(), self.connection.remote_address());
+        let stopped = send.stopped();
+        self.delivering.lock().unwrap().push(tokio::spawn(async move {
+            let _ = stopped.await;
+        }));
+        Ok((Box::new(tokio::io::join(recv, send)), description))
+    }
+
+    /// Smoothed round trip time of the connection.
+    pub fn rtt(&self) -> Duration {
+        self.connection.rtt()
+    }
+
+    /// Closes the connection once the data of all streams arrived, and waits until the peer has been told.
+    /// Closing right away would discard what is still on its way, e.g. the end of an upload.
+    pub async fn close(&self) {
+        let delivering: Vec<_> = self.delivering.lock().unwrap().drain(..).collect();
+        let delivered = async {
+            for stream in delivering {
+                let _ = stream.await;
+            }
+        };
+        let _ = timeout(DELIVERY_TIMEOUT, delivered).await;
+        self.connection.close(VarInt::from_u32(0), b"done");
+        self.endpoint.wait_idle().await;
+    }
+}
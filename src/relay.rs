@@ -0,0 +1,462 @@
+use num_format::Locale;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant, sleep, sleep_until, timeout};
+
+use crate::{
+    limits::RateLimiter,
+    net::{BoxStream, ConnectOptions, ListenAddress, Listener, NetConnector, Peer, Target, bind_listener, bind_udp},
+    signals::Terminate,
+    transport::{Acceptor, Connector},
+    utils::format_number,
+};
+
+/// Bytes read at once from a relayed stream, and the largest datagram.
+const CHUNK: usize = 64 * 1024;
+
+/// Chunks or datagrams on their way per direction and connection. A full queue holds back a
+/// stream and drops datagrams, like the buffer of a router.
+const QUEUE: usize = 1024;
+
+/// A UDP flow without datagrams from its client for this long is forgotten.
+const UDP_IDLE: Duration = Duration::from_secs(60);
+
+/// Pause after a failed accept, e.g. while out of file descriptors, instead of retrying at once.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Conditions the relay adds to the link, in each direction.
+#[derive(Clone, Debug, Default)]
+pub struct Impairment {
+    pub delay: Duration,
+    /// Each chunk or datagram is delayed by up to this much more or less than `delay`.
+    pub jitter: Duration,
+    /// Cap of each direction in MBit/s, shared by all connections.
+    pub bandwidth_mbit: Option<f64>,
+    /// Share of datagrams dropped, from 0 to 1. Streams are never lossy: the relay ends their TCP
+    /// connections itself, so losses would only show as retransmissions on the other side.
+    pub loss: f64,
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |duration: Duration| format_number(duration.as_secs_f64() * 1000.0, &Locale::de);
+        write!(f, "delay {} ms", ms(self.delay))?;
+        if !self.jitter.is_zero() {
+            write!(f, " ± {} ms", ms(self.jitter))?;
+        }
+        if let Some(mbit) = self.bandwidth_mbit {
+            write!(f, ", {} MBit/s", format_number(mbit, &Locale::de))?;
+        }
+        if self.loss > 0.0 {
+            write!(f, ", {} % UDP loss", format_number(self.loss * 100.0, &Locale::de))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct RelayOptions {
+    pub bind: Option<ListenAddress>,
+    pub port: u16,
+    /// Server to forward to, as HOST:PORT or unix:/path.
+    pub target: String,
+    /// Also relay UDP datagrams (QUIC) on the same port.
+    pub udp: bool,
+    pub impairment: Impairment,
+    /// Log every relayed connection and UDP flow, and failures to reach the target.
+    pub verbose: bool,
+}
+
+/// Datagrams the relay received from either side, and how many of them it dropped.
+#[derive(Debug, Default)]
+pub struct UdpStats {
+    pub datagrams: AtomicUsize,
+    pub lost: AtomicUsize,
+}
+
+/// The impairment and the bandwidth caps all connections of a relay share.
+struct Link {
+    impairment: Impairment,
+    up: Option<RateLimiter>,
+    down: Option<RateLimiter>,
+    udp: Arc<UdpStats>,
+    verbose: bool,
+}
+
+impl Link {
+    /// Counts a datagram, returns true if it is to be dropped.
+    fn lose(&self, delays: &mut Delays) -> bool {
+        self.udp.datagrams.fetch_add(1, Ordering::Relaxed);
+        let lost = delays.lose(&self.impairment);
+        if lost {
+            self.udp.lost.fetch_add(1, Ordering::Relaxed);
+        }
+        lost
+    }
+}
+
+/// Clients' UDP flows by their address, each with its queue towards the target.
+type Flows = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<(Instant, Vec<u8>)>>>>;
+
+/// Small xorshift generator for jitter and loss, seeded from the system. Drawing from the
+/// system for every datagram would cost a syscall each.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Random {
+        let mut bytes = [0u8; 8];
+        SystemRandom::new().fill(&mut bytes).expect("No randomness available");
+        Random(u64::from_le_bytes(bytes) | 1)
+    }
+
+    /// Uniform in [0, 1).
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// When the chunks or datagrams of one direction are due. Jitter never lets one overtake the one
+/// before, as a stream has to keep its order and reordered datagrams are not what we emulate.
+struct Delays {
+    random: Random,
+    last: Instant,
+}
+
+impl Delays {
+    fn new() -> Delays {
+        Delays {
+            random: Random::new(),
+            last: Instant::now(),
+        }
+    }
+
+    fn due(&mut self, impairment: &Impairment) -> Instant {
+        let jitter = impairment.jitter.as_secs_f64() * (2.0 * self.random.next() - 1.0);
+        let delay = Duration::from_secs_f64((impairment.delay.as_secs_f64() + jitter).max(0.0));
+        self.last = self.last.max(Instant::now() + delay);
+        self.last
+    }
+
+    fn lose(&mut self, impairment: &Impairment) -> bool {
+        impairment.loss > 0.0 && self.random.next() < impairment.loss
+    }
+}
+
+/// A bound relay, forwarding once [`Relay::run`] is called.
+pub struct Relay {
+    listener: Listener,
+    udp: Option<(UdpSocket, SocketAddr)>,
+    connector: Arc<NetConnector>,
+    link: Arc<Link>,
+}
+
+impl Relay {
+    /// Resolves the target and binds the listener, and with `udp` the UDP socket next to it.
+    /// Fails with `InvalidInput` unless the loss is between 0 and 1.
+    pub async fn bind(options: RelayOptions) -> io::Result<Relay> {
+        let loss = options.impairment.loss;
+        if !(loss.is_finite() && (0.0..=1.0).contains(&loss)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Loss must be between 0 and 1, not {}", loss)));
+        }
+        let connector = NetConnector::resolve(&options.target, ConnectOptions::default()).await?;
+        let listener = bind_listener(options.bind.as_ref(), options.port, None, false)?;
+
+        let udp = match (options.udp, &connector.target) {
+            (false, _) => None,
+            (true, Target::Tcp { addrs, .. }) => {
+                // Same port as the listener, which may have been picked by the system
+                let port = listener.local_addr().map_or(options.port, |addr| addr.port());
+                let socket = UdpSocket::from_std(bind_udp(options.bind.as_ref(), port, None)?)?;
+                Some((socket, addrs[0]))
+            }
+            (true, _) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "UDP can only be relayed to a HOST:PORT target")),
+        };
+
+        let impairment = options.impairment;
+        let limiter = || impairment.bandwidth_mbit.map(RateLimiter::new);
+        let link = Link {
+            up: limiter(),
+            down: limiter(),
+            impairment,
+            udp: Arc::default(),
+            verbose: options.verbose,
+        };
+        Ok(Relay {
+            listener,
+            udp,
+            connector: Arc::new(connector),
+            link: Arc::new(link),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn describe(&self) -> String {
+        self.listener.describe()
+    }
+
+    /// Datagrams relayed so far, which keep counting while [`Relay::run`] runs.
+    pub fn udp_stats(&self) -> Arc<UdpStats> {
+        Arc::clone(&self.link.udp)
+    }
+
+    /// Forwards every connection and UDP flow until dropped.
+    pub async fn run(self) {
+        let Relay { listener, udp, connector, link } = self;
+        if let Some((socket, target)) = udp {
+            tokio::spawn(relay_udp(Arc::new(socket), target, Arc::clone(&link)));
+        }
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tokio::spawn(relay_stream(stream, peer, Arc::clone(&connector), Arc::clone(&link)));
+                }
+                Err(e) => {
+                    if link.verbose {
+                        eprintln!("Failed to accept connection: {}", e);
+                    }
+                    sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+/// Runs the relay of the `relay` command until SIGINT or SIGTERM. Fails if it cannot bind.
+pub async fn run_relay(options: RelayOptions) -> io::Result<()> {
+    let target = options.target.clone();
+    let udp = options.udp;
+    println!("Impairment: {}", options.impairment);
+    let relay = Relay::bind(options).await?;
+    println!("Relay listening on {}{} ...", relay.describe(), if udp { " and UDP" } else { "" });
+    println!("Forwarding to {}", target);
+
+    let stats = relay.udp_stats();
+    let mut terminate = Terminate::new();
+    tokio::select! {
+        _ = relay.run() => {}
+        _ = terminate.recv() => println!("Termination signal received. Exiting relay."),
+    }
+    if udp {
+        println!(
+            "{} UDP datagrams, {} of them dropped",
+            format_number(stats.datagrams.load(Ordering::Relaxed) as f64, &Locale::de),
+            format_number(stats.lost.load(Ordering::Relaxed) as f64, &Locale::de)
+        );
+    }
+    Ok(())
+}
+
+async fn relay_stream(client: BoxStream, peer: Peer, connector: Arc<NetConnector>, link: Arc<Link>) {
+    let connection = match connector.connect(0).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            if link.verbose {
+                eprintln!("Failed to connect {} to the target: {}", peer.addr, e);
+            }
+            return;
+        }
+    };
+    if link.verbose {
+        println!("Relaying {} connection from {} via {}", peer.transport, peer.addr, connection.description);
+    }
+
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = tokio::io::split(connection.stream);
+    let broken = watch::Sender::new(false);
+    let (up, down) = tokio::join!(
+        pipe(client_read, server_write, &link.impairment, link.up.as_ref(), &broken),
+        pipe(server_read, client_write, &link.impairment, link.down.as_ref(), &broken)
+    );
+    println!(
+        "Connection from {} closed, {} bytes up and {} bytes down",
+        peer.addr,
+        format_number(up as f64, &Locale::de),
+        format_number(down as f64, &Locale::de)
+    );
+}
+
+/// Forwards one direction of a stream until `from` ends, then shuts `to` down. A side that
+/// fails sets `broken`, which ends the other direction too, as no one may be left to end it.
+/// Returns the bytes delivered.
+async fn pipe(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin, impairment: &Impairment, limiter: Option<&RateLimiter>, broken: &watch::Sender<bool>) -> usize {
+    let (queue, mut queued) = mpsc::channel::<(Instant, Vec<u8>)>(QUEUE);
+
+    let read = async move {
+        let mut delays = Delays::new();
+        let mut buf = vec![0u8; CHUNK];
+        let mut stop = broken.subscribe();
+        loop {
+            let n = tokio::select! {
+                read = from.read(&mut buf) => match read {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(_) => {
+                        broken.send_replace(true);
+                        break;
+                    }
+                },
+                _ = stop.wait_for(|broken| *broken) => break,
+            };
+            if let Some(limiter) = limiter {
+                limiter.acquire(n).await;
+            }
+            if queue.send((delays.due(impairment), buf[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let write = async move {
+        let mut bytes = 0;
+        while let Some((due, chunk)) = queued.recv().await {
+            sleep_until(due).await;
+            if to.write_all(&chunk).await.is_err() {
+                broken.send_replace(true);
+                return bytes;
+            }
+            bytes += chunk.len();
+        }
+        let _ = to.shutdown().await;
+        bytes
+    };
+
+    tokio::join!(read, write).1
+}
+
+/// Forwards datagrams from clients on `socket` to `target`, each client from its own socket so
+/// the replies find their way back.
+async fn relay_udp(socket: Arc<UdpSocket>, target: SocketAddr, link: Arc<Link>) {
+    let flows = Flows::default();
+    let mut delays = Delays::new();
+    let mut buf = vec![0u8; CHUNK];
+
+    loop {
+        let (n, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                if link.verbose {
+                    eprintln!("Failed to receive UDP datagram: {}", e);
+                }
+                continue;
+            }
+        };
+        if link.lose(&mut delays) {
+            continue;
+        }
+        if let Some(limiter) = &link.up {
+            limiter.acquire(n).await;
+        }
+
+        let datagram = (delays.due(&link.impairment), buf[..n].to_vec());
+        let datagram = match flows.lock().unwrap().get(&client) {
+            Some(flow) => match flow.try_send(datagram) {
+                Ok(()) | Err(TrySendError::Full(_)) => continue,
+                // The flow went idle just now, the client gets a new one
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+        match open_flow(Arc::clone(&socket), client, target, Arc::clone(&link), Arc::clone(&flows)).await {
+            Ok(flow) => {
+                let _ = flow.try_send(datagram);
+                flows.lock().unwrap().insert(client, flow);
+            }
+            Err(e) if link.verbose => eprintln!("Failed to open UDP flow from {} to {}: {}", client, target, e),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Opens the socket of a new UDP flow and starts forwarding in both directions. Once idle, the
+/// flow removes itself from `flows`. Returns the queue of datagrams towards the target.
+async fn open_flow(socket: Arc<UdpSocket>, client: SocketAddr, target: SocketAddr, link: Arc<Link>, flows: Flows) -> io::Result<mpsc::Sender<(Instant, Vec<u8>)>> {
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let upstream = Arc::new(UdpSocket::bind(local).await?);
+    upstream.connect(target).await?;
+    if link.verbose {
+        println!("Relaying UDP flow from {} via {} -> {}", client, upstream.local_addr()?, target);
+    }
+
+    let (up, mut up_queued) = mpsc::channel::<(Instant, Vec<u8>)>(QUEUE);
+    let (sender, own) = (Arc::clone(&upstream), up.clone());
+    tokio::spawn(async move {
+        while let Ok(Some((due, datagram))) = timeout(UDP_IDLE, up_queued.recv()).await {
+            sleep_until(due).await;
+            let _ = sender.send(&datagram).await;
+        }
+        // Unless the client already has a new flow
+        let mut flows = flows.lock().unwrap();
+        if flows.get(&client).is_some_and(|flow| flow.same_channel(&own)) {
+            flows.remove(&client);
+        }
+    });
+
+    let (down, mut down_queued) = mpsc::channel::<(Instant, Vec<u8>)>(QUEUE);
+    tokio::spawn(async move {
+        while let Some((due, datagram)) = down_queued.recv().await {
+            sleep_until(due).await;
+            let _ = socket.send_to(&datagram, client).await;
+        }
+    });
+    tokio::spawn(async move {
+        let mut delays = Delays::new();
+        let mut buf = vec![0u8; CHUNK];
+        while let Ok(Ok(n)) = timeout(UDP_IDLE, upstream.recv(&mut buf)).await {
+            if link.lose(&mut delays) {
+                continue;
+            }
+            if let Some(limiter) = &link.down {
+                limiter.acquire(n).await;
+            }
+            let _ = down.try_send((delays.due(&link.impairment), buf[..n].to_vec()));
+        }
+    });
+
+    Ok(up)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delays() {
+        let impairment = Impairment {
+            delay: Duration::from_millis(50),
+            jitter: Duration::from_millis(20),
+            ..Impairment::default()
+        };
+        let mut delays = Delays::new();
+        let start = Instant::now();
+        let mut last = start;
+        for _ in 0..1000 {
+            let due = delays.due(&impairment);
+            assert!(due >= last, "Jitter must not reorder");
+            assert!(due - start >= Duration::from_millis(30) && due - start <= Duration::from_millis(70));
+            last = due;
+        }
+
+        let lossy = Impairment { loss: 0.25, ..impairment };
+        let lost = (0..10_000).filter(|_| delays.lose(&lossy)).count();
+        assert!((2000..3000).contains(&lost), "{}", lost);
+        assert!(!(0..1000).any(|_| delays.lose(&Impairment::default())));
+    }
+}
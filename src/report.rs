@@ -0,0 +1,389 @@
+use chrono::{DateTime, Local};
+use num_format::Locale;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use speedtest::{
+    Direction,
+    history::Aggregate,
+    sink::Measurement,
+    utils::{format_number, median},
+};
+
+/// Runs per direction shown in the per-stream breakdown.
+const STREAM_RUNS: usize = 10;
+
+const WIDTH: f64 = 800.0;
+const HEIGHT: f64 = 300.0;
+const LEFT: f64 = 70.0;
+const RIGHT: f64 = 40.0;
+const TOP: f64 = 20.0;
+const BOTTOM: f64 = 40.0;
+
+const COLORS: [&str; 8] = ["#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#17becf"];
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 860px; color: #222; }
+h2 { margin-top: 2em; border-bottom: 1px solid #ccc; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { padding: 0.3em 0.8em; text-align: right; border-bottom: 1px solid #eee; }
+th:first-child, td:first-child { text-align: left; }
+svg { font-size: 11px; }
+.grid { stroke: #e5e5e5; }
+.axis { stroke: #888; }
+.legend span { display: inline-block; margin-right: 1.5em; }
+.legend i { display: inline-block; width: 12px; height: 12px; margin-right: 0.4em; vertical-align: middle; }
+";
+
+/// Line of a chart, points as `(x, y, tooltip)`.
+struct Series {
+    name: String,
+    color: &'static str,
+    points: Vec<(f64, f64, String)>,
+}
+
+/// Self-contained HTML page with tables and inline SVG charts of `measurements`.
+/// Network tests are grouped by target, file tests become size sweep curves.
+pub fn render(measurements: &[Measurement]) -> String {
+    let locale = Locale::de;
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"de\">\n<head>\n<meta charset=\"utf-8\">\n<title>Speedtest-Bericht</title>\n<style>{}</style>\n</head>\n<body>\n<h1>Speedtest-Bericht</h1>\n",
+        STYLE
+    );
+
+    let _ = write!(html, "<p>Erstellt am {}", Local::now().format("%d.%m.%Y %H:%M"));
+    if let (Some(first), Some(last)) = (measurements.first(), measurements.last()) {
+        let _ = write!(
+            html,
+            ", {} Messungen vom {} bis {}",
+            measurements.len(),
+            first.timestamp.format("%d.%m.%Y %H:%M"),
+            last.timestamp.format("%d.%m.%Y %H:%M")
+        );
+    }
+    html.push_str("</p>\n");
+
+    let mut network: BTreeMap<&str, Vec<&Measurement>> = BTreeMap::new();
+    let mut files: BTreeMap<&str, Vec<&Measurement>> = BTreeMap::new();
+    for measurement in measurements {
+        let targets = if measurement.is_file_test() { &mut files } else { &mut network };
+        targets.entry(&measurement.target).or_default().push(measurement);
+    }
+    if network.is_empty() && files.is_empty() {
+        html.push_str("<p>Keine Messungen gefunden.</p>\n");
+    }
+
+    for (target, measurements) in network {
+        let by_direction = by_direction(&measurements);
+        let _ = writeln!(html, "<h2>{}</h2>", escape(target));
+
+        html.push_str("<table>\n<tr><th>Richtung</th><th>Läufe</th><th>Mittel</th><th>Median</th><th>P95</th><th>Latenz Ø</th><th>Fehlerhafte Streams</th></tr>\n");
+        for (direction, measurements) in &by_direction {
+            let aggregate = Aggregate::new(measurements);
+            let _ = writeln!(
+                html,
+                "<tr><td>{:?}</td><td>{}</td><td>{} MBit/s</td><td>{} MBit/s</td><td>{} MBit/s</td><td>{}</td><td>{}</td></tr>",
+                direction,
+                aggregate.runs,
+                format_number(aggregate.mean, &locale),
+                format_number(aggregate.median, &locale),
+                format_number(aggregate.p95, &locale),
+                aggregate.latency_ms.map_or("-".to_string(), |latency| format!("{} ms", format_number(latency, &locale))),
+                aggregate.errors
+            );
+        }
+        html.push_str("</table>\n");
+
+        let series: Vec<Series> = by_direction
+            .iter()
+            .enumerate()
+            .map(|(index, (direction, measurements))| Series {
+                name: format!("{:?}", direction),
+                color: COLORS[index % COLORS.len()],
+                points: measurements
+                    .iter()
+                    .map(|measurement| {
+                        let tooltip = format!(
+                            "{} {:?}: {} MBit/s",
+                            measurement.timestamp.format("%d.%m.%Y %H:%M:%S"),
+                            direction,
+                            format_number(measurement.mbit_per_sec(), &locale)
+                        );
+                        (measurement.timestamp.timestamp() as f64, measurement.mbit_per_sec(), tooltip)
+                    })
+                    .collect(),
+            })
+            .collect();
+        html.push_str("<h3>Durchsatz über die Zeit</h3>\n");
+        html.push_str(&line_chart(&series, &time_ticks(&series)));
+
+        for (direction, measurements) in &by_direction {
+            let recent: Vec<&&Measurement> = measurements.iter().filter(|measurement| !measurement.stream_bytes.is_empty()).collect();
+            let recent = &recent[recent.len().saturating_sub(STREAM_RUNS)..];
+            if recent.is_empty() {
+                continue;
+            }
+            let groups: Vec<(String, Vec<f64>)> = recent
+                .iter()
+                .map(|measurement| (measurement.timestamp.format("%d.%m. %H:%M").to_string(), measurement.stream_mbit_per_sec()))
+                .collect();
+            let _ = writeln!(html, "<h3>Durchsatz je Stream, {:?}, letzte {} Läufe</h3>", direction, recent.len());
+            html.push_str(&bar_chart(&groups));
+        }
+    }
+
+    for (target, measurements) in files {
+        let _ = writeln!(html, "<h2>Datei {}</h2>", escape(target));
+        let series: Vec<Series> = by_direction(&measurements)
+            .into_iter()
+            .enumerate()
+            .map(|(index, (direction, measurements))| {
+                let name = match direction {
+                    Direction::Upload => "Schreiben",
+                    _ => "Lesen",
+                };
+                let mut by_size: BTreeMap<usize, Vec<f64>> = BTreeMap::new();
+                for measurement in measurements {
+                    by_size.entry(measurement.bytes).or_default().push(measurement.mbit_per_sec());
+                }
+                let points = by_size
+                    .into_iter()
+                    .map(|(size, mbits)| {
+                        let mbit = median(&mbits);
+                        let tooltip = format!("{} {}: {} MBit/s (Median aus {})", name, format_size(size), format_number(mbit, &locale), mbits.len());
+                        ((size as f64).log2(), mbit, tooltip)
+                    })
+                    .collect();
+                Series {
+                    name: name.to_string(),
+                    color: COLORS[index % COLORS.len()],
+                    points,
+                }
+            })
+            .collect();
+
+        html.push_str("<h3>Durchsatz nach Dateigröße (Median)</h3>\n");
+        let mut exponents: Vec<f64> = series.iter().flat_map(|series| series.points.iter().map(|point| point.0)).collect();
+        exponents.sort_by(f64::total_cmp);
+        exponents.dedup();
+        let ticks = exponents.iter().map(|exponent| (*exponent, format_size(exponent.exp2().round() as usize))).collect::<Vec<_>>();
+        html.push_str(&line_chart(&series, &ticks));
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn by_direction<'a>(measurements: &[&'a Measurement]) -> BTreeMap<Direction, Vec<&'a Measurement>> {
+    let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for measurement in measurements {
+        groups.entry(measurement.direction).or_default().push(*measurement);
+    }
+    groups
+}
+
+/// Up to six evenly spaced time labels over the range of `series`.
+fn time_ticks(series: &[Series]) -> Vec<(f64, String)> {
+    let (min, max) = x_range(series);
+    let label = |x: f64| DateTime::from_timestamp(x as i64, 0).map_or(String::new(), |time| time.with_timezone(&Local).format("%d.%m. %H:%M").to_string());
+    match max > min {
+        true => (0..6).map(|step| min + (max - min) * step as f64 / 5.0).map(|x| (x, label(x))).collect(),
+        false => vec![(min, label(min))],
+    }
+}
+
+fn x_range(series: &[Series]) -> (f64, f64) {
+    let xs = series.iter().flat_map(|series| series.points.iter().map(|point| point.0));
+    xs.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)))
+}
+
+/// Smallest 1, 2 or 5 times a power of ten at or above `value`, so the axis gets round labels.
+fn nice_max(value: f64) -> f64 {
+    if value <= 0.0 || !value.is_finite() {
+        return 1.0;
+    }
+    let magnitude = 10f64.powf(value.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter().map(|factor| factor * magnitude).find(|nice| *nice >= value).unwrap_or(10.0 * magnitude)
+}
+
+/// Empty chart with horizontal grid lines up to `y_max` MBit/s. Returns the SVG head and the y scale.
+fn chart_frame(y_max: f64) -> (String, impl Fn(f64) -> f64) {
+    let plot_height = HEIGHT - TOP - BOTTOM;
+    let y = move |value: f64| TOP + plot_height * (1.0 - value / y_max);
+
+    let mut svg = format!(
+        "<svg viewBox=\"0 0 {} {}\" width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">\n",
+        WIDTH, HEIGHT, WIDTH, HEIGHT
+    );
+    for step in 0..=5 {
+        let value = y_max * step as f64 / 5.0;
+        let _ = writeln!(
+            svg,
+            "<line class=\"grid\" x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\"/><text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+            LEFT,
+            y(value),
+            WIDTH - RIGHT,
+            y(value),
+            LEFT - 6.0,
+            y(value) + 4.0,
+            format_number(value, &Locale::de)
+        );
+    }
+    let _ = writeln!(
+        svg,
+        "<text x=\"14\" y=\"{:.1}\" transform=\"rotate(-90 14 {:.1})\" text-anchor=\"middle\">MBit/s</text>",
+        TOP + plot_height / 2.0,
+        TOP + plot_height / 2.0
+    );
+    let _ = writeln!(
+        svg,
+        "<line class=\"axis\" x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\"/>",
+        LEFT,
+        HEIGHT - BOTTOM,
+        WIDTH - RIGHT,
+        HEIGHT - BOTTOM
+    );
+    (svg, y)
+}
+
+fn line_chart(series: &[Series], x_ticks: &[(f64, String)]) -> String {
+    let (x_min, x_max) = x_range(series);
+    let (x_min, x_max) = if x_max > x_min { (x_min, x_max) } else { (x_min - 1.0, x_min + 1.0) };
+    let y_max = nice_max(series.iter().flat_map(|series| series.points.iter().map(|point| point.1)).fold(0.0, f64::max));
+    let x = |value: f64| LEFT + (WIDTH - LEFT - RIGHT) * (value - x_min) / (x_max - x_min);
+
+    let (mut svg, y) = chart_frame(y_max);
+    for (value, label) in x_ticks {
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>", x(*value), HEIGHT - BOTTOM + 16.0, escape(label));
+    }
+    for series in series {
+        let points: Vec<String> = series.points.iter().map(|point| format!("{:.1},{:.1}", x(point.0), y(point.1))).collect();
+        let _ = writeln!(svg, "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"2\" points=\"{}\"/>", series.color, points.join(" "));
+        for point in &series.points {
+            let _ = writeln!(
+                svg,
+                "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"><title>{}</title></circle>",
+                x(point.0),
+                y(point.1),
+                series.color,
+                escape(&point.2)
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+
+    let legend = series.iter().map(|series| (series.name.clone(), series.color)).collect::<Vec<_>>();
+    svg + &legend_html(&legend)
+}
+
+/// One group of bars per labelled run, one bar per stream.
+fn bar_chart(groups: &[(String, Vec<f64>)]) -> String {
+    let y_max = nice_max(groups.iter().flat_map(|group| group.1.iter().copied()).fold(0.0, f64::max));
+    let streams = groups.iter().map(|group| group.1.len()).max().unwrap_or(0);
+    let group_width = (WIDTH - LEFT - RIGHT) / groups.len() as f64;
+    let bar_width = group_width * 0.8 / streams.max(1) as f64;
+
+    let (mut svg, y) = chart_frame(y_max);
+    for (index, (label, mbits)) in groups.iter().enumerate() {
+        let group_x = LEFT + group_width * index as f64;
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+            group_x + group_width / 2.0,
+            HEIGHT - BOTTOM + 16.0,
+            escape(label)
+        );
+        for (stream, mbit) in mbits.iter().enumerate() {
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"><title>Stream {}: {} MBit/s</title></rect>",
+                group_x + group_width * 0.1 + bar_width * stream as f64,
+                y(*mbit),
+                bar_width,
+                y(0.0) - y(*mbit),
+                COLORS[stream % COLORS.len()],
+                stream + 1,
+                format_number(*mbit, &Locale::de)
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+
+    let legend = (0..streams).map(|stream| (format!("Stream {}", stream + 1), COLORS[stream % COLORS.len()])).collect::<Vec<_>>();
+    svg + &legend_html(&legend)
+}
+
+fn legend_html(entries: &[(String, &str)]) -> String {
+    let mut html = String::from("<div class=\"legend\">");
+    for (name, color) in entries {
+        let _ = write!(html, "<span><i style=\"background:{}\"></i>{}</span>", color, escape(name));
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+/// Sizes from `generate_test_sizes` are powers of two, so binary units keep the labels short.
+fn format_size(bytes: usize) -> String {
+    let (value, unit) = match bytes {
+        bytes if bytes >= 1 << 30 => (bytes as f64 / (1u64 << 30) as f64, "GiB"),
+        bytes if bytes >= 1 << 20 => (bytes as f64 / (1u64 << 20) as f64, "MiB"),
+        bytes if bytes >= 1 << 10 => (bytes as f64 / (1u64 << 10) as f64, "KiB"),
+        bytes => (bytes as f64, "B"),
+    };
+    format!("{} {}", format_number(value, &Locale::de), unit)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_render() {
+        let start = Local::now();
+        let network = |minutes: i64, direction: Direction| Measurement {
+            timestamp: start + Duration::minutes(minutes),
+            target: "<server>:4000".to_string(),
+            direction,
+            block_size_kb: 64,
+            duration_secs: 1.0,
+            bytes: 25_000_000,
+            latency_ms: Some(0.4),
+            errors: 0,
+            stream_bytes: vec![12_500_000; 2],
+        };
+        let file = |size: usize, direction: Direction| Measurement {
+            block_size_kb: 0,
+            target: "./testfile.txt".to_string(),
+            bytes: size,
+            stream_bytes: Vec::new(),
+            latency_ms: None,
+            ..network(10, direction)
+        };
+
+        let html = render(&[
+            network(0, Direction::Download),
+            network(5, Direction::Download),
+            network(5, Direction::Upload),
+            file(4096, Direction::Upload),
+            file(1 << 20, Direction::Upload),
+            file(1 << 20, Direction::Download),
+        ]);
+
+        assert!(html.contains("<h2>&lt;server&gt;:4000</h2>"));
+        assert!(html.contains("<td>Download</td><td>2</td><td>200 MBit/s</td>"));
+        assert!(html.contains("Durchsatz je Stream, Upload, letzte 1 Läufe"));
+        assert!(html.contains("<title>Stream 2: 100 MBit/s</title>"));
+        assert!(html.contains("<h2>Datei ./testfile.txt</h2>"));
+        assert!(html.contains(">4 KiB</text>") && html.contains(">1 MiB</text>"));
+        assert_eq!(html.matches("<svg").count(), 4);
+        assert_eq!(nice_max(730.0), 1000.0);
+        assert_eq!(nice_max(120.0), 200.0);
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, NaiveDateTime, TimeZone, Timelike};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When monitor cycles start.
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    /// Each cycle right after the previous one.
+    Continuous,
+    /// Cycles start this far apart, or right away if the previous one ran longer.
+    Interval(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// Start of the next cycle, given the start of the previous one.
+    pub fn next(&self, previous: Option<DateTime<Local>>, now: DateTime<Local>) -> DateTime<Local> {
+        match (self, previous) {
+            (Schedule::Continuous, _) | (Schedule::Interval(_), None) => now,
+            (Schedule::Interval(interval), Some(previous)) => (previous + ChronoDuration::from_std(*interval).unwrap_or(ChronoDuration::MAX)).max(now),
+            (Schedule::Cron(cron), _) => cron.next_after(now),
+        }
+    }
+}
+
+/// Cron-like schedule with the five fields `minute hour day-of-month month day-of-week`.
+/// Fields accept `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and comma separated lists.
+/// Day of week is 0-7 with 0 and 7 meaning Sunday.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Like cron, a restricted day of month and day of week match if either one matches.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields.as_slice() else {
+            return Err(format!("Invalid schedule '{}', expected 5 fields: minute hour day-of-month month day-of-week", s));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is another name for Sunday
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+
+        Ok(Cron {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            days_restricted: *day != "*",
+            weekdays_restricted: *weekday != "*",
+        })
+    }
+}
+
+/// Values of one field as flags indexed by value.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut values = vec![false; max as usize + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(|| format!("Invalid step in '{}'", part))?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start, part)?, parse_value(end, part)?),
+                None => (parse_value(range, part)?, parse_value(range, part)?),
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("'{}' is outside {}-{}", part, min, max));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            values[value as usize] = true;
+        }
+    }
+
+    Ok(values)
+}
+
+fn parse_value(value: &str, part: &str) -> Result<u32, String> {
+    value.parse().map_err(|_| format!("Invalid value in '{}'", part))
+}
+
+impl Cron {
+    fn matches(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+
+        day_matches && self.minutes[time.minute() as usize] && self.hours[time.hour() as usize] && self.months[time.month() as usize]
+    }
+
+    /// First matching minute after `now`. Local times skipped by a DST change never match.
+    pub fn next_after(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let mut time = now.naive_local().with_second(0).and_then(|time| time.with_nanosecond(0)).expect("Valid time") + ChronoDuration::minutes(1);
+
+        // Every valid expression matches within a few years (e.g. February 29)
+        for _ in 0..5 * 366 * 24 * 60 {
+            if self.matches(&time)
+                && let Some(local) = Local.from_local_datetime(&time).earliest()
+            {
+                return local;
+            }
+            time += ChronoDuration::minutes(1);
+        }
+
+        panic!("Schedule never matches");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_next() {
+        let at = |s: &str| Local.from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()).unwrap();
+
+        let every_quarter: Cron = "*/15 * * * *".parse().unwrap();
+        assert_eq!(every_quarter.next_after(at("2024-03-04 10:07:30")), at("2024-03-04 10:15:00"));
+        assert_eq!(every_quarter.next_after(at("2024-03-04 10:15:00")), at("2024-03-04 10:30:00"));
+
+        // 2024-03-04 is a Monday, weekdays at 02:30 only
+        let nightly: Cron = "30 2 * * 1-5".parse().unwrap();
+        assert_eq!(nightly.next_after(at("2024-03-08 03:00:00")), at("2024-03-11 02:30:00"));
+
+        let sunday: Cron = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sunday.next_after(at("2024-03-04 00:00:00")), at("2024-03-10 00:00:00"));
+
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+    }
+}
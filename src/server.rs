@@ -1,144 +1,857 @@
 use num_format::Locale;
-use std::sync::atomic::Ordering;
-use std::sync::{Arc, atomic::AtomicUsize};
-use std::time::Instant;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
 
 use crate::{
-    Direction,
-    utils::{format_number, print_statistics_terminal},
+    Direction, agent,
+    auth::{self, Key, ServerAuth},
+    clock::{Clock, TokioClock},
+    engine::{Role, StreamTest, Transfer, run_stream},
+    limits::{Limits, SessionSummary, Sessions},
+    live::{Meters, SocketProbe},
+    metrics::{self, Metrics, Rejection},
+    net::{BoxStream, ListenAddress, Peer, bind_listener},
+    protocol::{Reply, Request, read_line, read_request, send_reply},
+    quic,
+    signals::{Hangup, Terminate},
+    tls::{self, Verification},
+    transport::Acceptor,
+    tui::Tui,
+    utils::{Statistics, format_number, print_statistics_terminal, write_statistics_terminal},
 };
+use tokio_rustls::rustls::ServerConfig;
 
-pub async fn run_server(port: u16, block_size_kb: usize, default_duration_secs: u64) {
-    let listener = TcpListener::bind(("0.0.0.0", port)).await.expect("Failed to bind");
-    println!("Server listening on port {} ...", port);
+/// Time a client gets to answer the authentication challenge.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
 
-    let total_bytes = Arc::new(AtomicUsize::new(0));
-    let total_duration = Arc::new(Mutex::new(Duration::ZERO));
-    let clients = Arc::new(Mutex::new(0usize));
-    let (quit_tx, mut quit_rx) = tokio::sync::watch::channel(false);
+/// Time QUIC clients get to acknowledge the last data of their streams and close their connections.
+const QUIC_CLOSE_GRACE: Duration = Duration::from_secs(5);
 
-    loop {
-        tokio::select! {
-            Ok((socket, addr)) = listener.accept() => {
-                println!("Accepted connection from {}", addr);
-
-                let bytes = Arc::clone(&total_bytes);
-                let clients = Arc::clone(&clients);
-                let total_duration = Arc::clone(&total_duration);
-                let quit_signal = quit_tx.clone();
-
-                tokio::spawn(async move {
-                    let mut reader = BufReader::new(socket);
-                    let mut mode_line = String::new();
-                    let mut duration_line = String::new();
-
-                    if reader.read_line(&mut mode_line).await.unwrap_or(0) == 0 {
-                        eprintln!("Failed to read mode from client {}", addr);
-                        return;
-                    }
+/// Interval of position updates for clients waiting in the queue.
+const QUEUE_UPDATE: Duration = Duration::from_secs(5);
 
-                    let mode_str = mode_line.trim();
-                    let mode = match mode_str {
-                        "upload" => Direction::Upload,
-                        "download" => Direction::Download,
-                        "bidirectional" => Direction::Bidirectional,
-                        "quit" => Direction::Quit,
-                        _ => {
-                            eprintln!("Unknown direction '{}' from {}", mode_str, addr);
-                            return;
-                        }
-                    };
+/// Settings read from files, loaded again on SIGHUP. Limits and the other options are not reloaded.
+pub struct Settings {
+    pub tls_config: Option<Arc<ServerConfig>>,
+    pub quic_config: Option<quinn::ServerConfig>,
+    pub auth: ServerAuth,
+}
 
-                    if mode == Direction::Quit {
-                        println!("Quit signal received from {}", addr);
-                        let _ = quit_signal.send(true);
-                        return;
-                    }
+pub type LoadSettings = Box<dyn Fn() -> io::Result<Settings> + Send + Sync>;
 
-                    let mut duration_secs = default_duration_secs;
-                    if matches!(mode, Direction::Download | Direction::Bidirectional) {
-                        if reader.read_line(&mut duration_line).await.unwrap_or(0) == 0 {
-                            eprintln!("Expected duration line from client {}", addr);
-                            return;
-                        }
+pub struct ServerOptions {
+    pub bind: Option<ListenAddress>,
+    pub interface: Option<String>,
+    pub port: u16,
+    pub block_size_kb: usize,
+    pub load_settings: LoadSettings,
+    pub limits: Limits,
+    /// Exit after the first session that ends.
+    pub one_off: bool,
+    /// Time running streams get to finish after a shutdown was requested.
+    pub drain_timeout: Duration,
+    /// Address of the Prometheus metrics endpoint.
+    pub metrics: Option<SocketAddr>,
+    /// Accept run commands of a controller, see `agent`.
+    pub agent: bool,
+    /// How the agent checks the certificates of the servers it tests against.
+    pub agent_verification: Verification,
+    /// Log every connection (accepts, TLS handshakes, queue positions, disconnects) to stdout.
+    pub verbose: bool,
+    /// Sample `TCP_INFO` of every TCP stream for the live view.
+    pub probe: bool,
+    /// Shut down on SIGINT/SIGTERM and reload the settings on SIGHUP.
+    pub signals: bool,
+    /// Time source of the stream deadlines and durations.
+    pub clock: Arc<dyn Clock>,
+}
 
-                        match duration_line.trim().parse::<u64>() {
-                            Ok(secs) => duration_secs = secs,
-                            Err(_) => {
-                                eprintln!("Invalid duration from {}: '{}'", addr, duration_line.trim());
-                                return;
-                            }
+/// State shared by all connection handlers of one server.
+/// Every clone keeps the server draining until it is dropped.
+#[derive(Clone)]
+struct Shared {
+    block_size_kb: usize,
+    agent: bool,
+    agent_verification: Verification,
+    verbose: bool,
+    auth: Arc<RwLock<ServerAuth>>,
+    limits: Limits,
+    sessions: Sessions,
+    metrics: Arc<Metrics>,
+    meters: Arc<Meters>,
+    clock: Arc<dyn Clock>,
+    shutdown_tx: watch::Sender<bool>,
+    _running: mpsc::Sender<()>,
+}
+
+/// Builder for a server embedded in another program. Unlike the `server` command it leaves
+/// signals alone, stop it with [`RunningServer::shutdown`], and only logs connections if [`Server::verbose`].
+pub struct Server {
+    bind: Option<ListenAddress>,
+    interface: Option<String>,
+    port: u16,
+    block_size_kb: usize,
+    tls: Option<Arc<ServerConfig>>,
+    quic: Option<quinn::ServerConfig>,
+    auth: ServerAuth,
+    limits: Limits,
+    one_off: bool,
+    drain_timeout: Duration,
+    metrics: Option<SocketAddr>,
+    agent: bool,
+    agent_verification: Verification,
+    verbose: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+impl Server {
+    /// Plain TCP on port 4000 of all interfaces, without keys or limits, like the `server` command.
+    pub fn new() -> Server {
+        Server {
+            bind: None,
+            interface: None,
+            port: 4000,
+            block_size_kb: 64,
+            tls: None,
+            quic: None,
+            auth: ServerAuth::default(),
+            limits: Limits::default(),
+            one_off: false,
+            drain_timeout: Duration::from_secs(30),
+            metrics: None,
+            agent: false,
+            agent_verification: Verification::default(),
+            verbose: false,
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Port 0 picks a free one, see [`RunningServer::local_addr`].
+    pub fn port(mut self, port: u16) -> Server {
+        self.port = port;
+        self
+    }
+
+    pub fn bind(mut self, bind: ListenAddress) -> Server {
+        self.bind = Some(bind);
+        self
+    }
+
+    pub fn interface(mut self, interface: impl Into<String>) -> Server {
+        self.interface = Some(interface.into());
+        self
+    }
+
+    pub fn block_size_kb(mut self, block_size_kb: usize) -> Server {
+        self.block_size_kb = block_size_kb;
+        self
+    }
+
+    /// Accept TLS instead of plain TCP, see [`tls::server_config`].
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> Server {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Accept QUIC on the same port in addition, see [`quic::server_config`].
+    pub fn quic(mut self, config: quinn::ServerConfig) -> Server {
+        self.quic = Some(config);
+        self
+    }
+
+    pub fn auth(mut self, auth: ServerAuth) -> Server {
+        self.auth = auth;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+
+    /// Stop after the first session that ends.
+    pub fn one_off(mut self, one_off: bool) -> Server {
+        self.one_off = one_off;
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Server {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Serve Prometheus metrics on `addr`.
+    pub fn metrics(mut self, addr: SocketAddr) -> Server {
+        self.metrics = Some(addr);
+        self
+    }
+
+    /// Accept run commands of a controller, needs an admin or session key.
+    pub fn agent(mut self, agent: bool) -> Server {
+        self.agent = agent;
+        self
+    }
+
+    /// How the agent checks server certificates, against the web roots by default.
+    pub fn agent_verification(mut self, verification: Verification) -> Server {
+        self.agent_verification = verification;
+        self
+    }
+
+    /// Log every connection (accepts, TLS handshakes, queue positions, disconnects) like the `server` command.
+    pub fn verbose(mut self, verbose: bool) -> Server {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Time source of the stream deadlines and durations, tokio's clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Server {
+        self.clock = clock;
+        self
+    }
+
+    /// Binds the sockets and serves in the background.
+    pub async fn start(self) -> io::Result<RunningServer> {
+        let options = self.into_options();
+        start_server(options).await
+    }
+
+    /// Serves the streams of `acceptor` in the background, e.g. an in-memory [`crate::transport::memory`].
+    pub async fn start_with(self, acceptor: impl Acceptor + 'static) -> io::Result<RunningServer> {
+        let options = self.into_options();
+        start_server_on(Box::new(acceptor), options).await
+    }
+
+    fn into_options(self) -> ServerOptions {
+        let Server { tls, quic, auth, .. } = self;
+        let load_settings = move || {
+            Ok(Settings {
+                tls_config: tls.clone(),
+                quic_config: quic.clone(),
+                auth: auth.clone(),
+            })
+        };
+
+        ServerOptions {
+            bind: self.bind,
+            interface: self.interface,
+            port: self.port,
+            block_size_kb: self.block_size_kb,
+            load_settings: Box::new(load_settings),
+            limits: self.limits,
+            one_off: self.one_off,
+            drain_timeout: self.drain_timeout,
+            metrics: self.metrics,
+            agent: self.agent,
+            agent_verification: self.agent_verification,
+            verbose: self.verbose,
+            probe: false,
+            signals: false,
+            clock: self.clock,
+        }
+    }
+}
+
+/// A server serving in the background, see [`start_server`].
+pub struct RunningServer {
+    description: String,
+    local_addr: Option<SocketAddr>,
+    quic_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    sessions: Sessions,
+    meters: Arc<Meters>,
+    shutdown_tx: watch::Sender<bool>,
+    task: JoinHandle<ServerReport>,
+}
+
+impl RunningServer {
+    /// TCP address the server listens on, `None` for Unix sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// UDP address of the QUIC endpoint, if QUIC is enabled.
+    pub fn quic_addr(&self) -> Option<SocketAddr> {
+        self.quic_addr
+    }
+
+    /// Address of the metrics endpoint, if enabled.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Listen address with its family, as logged at startup.
+    pub fn describe(&self) -> &str {
+        &self.description
+    }
+
+    /// Running and queued sessions, and a summary of each one that ends.
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// Live byte counters of the running streams.
+    pub fn meters(&self) -> Arc<Meters> {
+        Arc::clone(&self.meters)
+    }
+
+    /// Stops accepting, like a quit command. Running streams get the drain timeout to finish.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    /// Waits until the server has stopped.
+    pub async fn wait(self) -> ServerReport {
+        self.task.await.expect("Server task failed")
+    }
+}
+
+/// What a server did until it stopped.
+#[derive(Clone, Debug)]
+pub struct ServerReport {
+    /// The session that ended a one-off server.
+    pub session: Option<SessionSummary>,
+    /// All streams of all sessions, over their summed run time.
+    pub statistics: Statistics,
+    one_off: bool,
+}
+
+impl ServerReport {
+    /// False if the one-off session failed or the server stopped before one ended.
+    pub fn succeeded(&self) -> bool {
+        match &self.session {
+            Some(session) => session.succeeded(),
+            None => !self.one_off,
+        }
+    }
+}
+
+/// Runs until quit, SIGINT/SIGTERM or, with `one_off`, until the first session ends, then prints the result.
+/// With `tui` the running streams are shown live. Returns false if the server did not start, see [`ServerReport::succeeded`].
+pub async fn run_server(options: ServerOptions, tui: bool) -> bool {
+    let options = ServerOptions { probe: tui, ..options };
+    let server = match start_server(options).await {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to start server: {}", e);
+            return false;
+        }
+    };
+    println!("Server listening on {} ...", server.describe());
+    if let Some(addr) = server.quic_addr() {
+        println!("QUIC listening on {} ...", addr);
+    }
+    if let Some(addr) = server.metrics_addr() {
+        println!("Metrics available at http://{}/metrics", addr);
+    }
+    let tui = tui
+        .then(|| {
+            let sessions = server.sessions().clone();
+            let status = move || {
+                let (running, queued) = sessions.counts();
+                format!("Sitzungen: {} laufend, {} wartend", running, queued)
+            };
+            Tui::start(server.meters(), format!("Server {}", server.describe()), None, Box::new(status))
+        })
+        .flatten();
+
+    let report = server.wait().await;
+    if let Some(tui) = tui {
+        tui.stop();
+    }
+
+    println!("\n[ERGEBNIS]");
+    if let Some(summary) = &report.session {
+        println!("Sitzung: {} ({:?}, {} von {} Streams)", summary.id, summary.direction, summary.joined, summary.announced);
+        match summary.succeeded() {
+            true => println!("Status: erfolgreich"),
+            false => println!("Status: fehlgeschlagen ({} Streams abgebrochen)", summary.failed),
+        }
+        print_statistics_terminal(summary.duration.as_secs_f64(), summary.bytes);
+    } else {
+        write_statistics_terminal(&report.statistics);
+    }
+    report.succeeded()
+}
+
+/// Binds the sockets and serves in the background until quit, a shutdown or, with `one_off`,
+/// until the first session ends.
+pub async fn start_server(options: ServerOptions) -> io::Result<RunningServer> {
+    let listener = bind_listener(options.bind.as_ref(), options.port, options.interface.as_deref(), options.probe)?;
+    start_server_on(Box::new(listener), options).await
+}
+
+/// Like [`start_server`], but serves the streams of `acceptor` instead of binding a listener.
+/// QUIC still binds its own socket if configured.
+pub async fn start_server_on(acceptor: Box<dyn Acceptor>, options: ServerOptions) -> io::Result<RunningServer> {
+    let ServerOptions {
+        bind,
+        interface,
+        port,
+        block_size_kb,
+        load_settings,
+        limits,
+        one_off,
+        drain_timeout,
+        metrics: metrics_addr,
+        agent,
+        agent_verification,
+        verbose,
+        signals,
+        clock,
+        ..
+    } = options;
+    let Settings { mut tls_config, quic_config, auth } = load_settings()?;
+    if agent && auth.admin_key().is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--agent requires --psk or --admin-psk, run commands must be authenticated"));
+    }
+
+    let description = acceptor.describe();
+
+    // On the same port as TCP, also when the system picked it
+    let quic_port = acceptor.local_addr().map_or(port, |addr| addr.port());
+    let quic_endpoint = match quic_config {
+        Some(config) => Some(quic::bind_server(bind.as_ref(), quic_port, interface.as_deref(), config)?),
+        None => None,
+    };
+    let quic_addr = match &quic_endpoint {
+        Some(endpoint) => Some(endpoint.local_addr()?),
+        None => None,
+    };
+    let metrics_listener = match metrics_addr {
+        Some(addr) => Some(
+            TcpListener::bind(addr)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind the metrics endpoint {}: {}", addr, e)))?,
+        ),
+        None => None,
+    };
+    let metrics_addr = match &metrics_listener {
+        Some(listener) => Some(listener.local_addr()?),
+        None => None,
+    };
+
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+    let (running_tx, mut running_rx) = mpsc::channel(1);
+    let shared = Shared {
+        block_size_kb,
+        agent,
+        agent_verification,
+        verbose,
+        auth: Arc::new(RwLock::new(auth)),
+        limits,
+        sessions: Sessions::default(),
+        metrics: Arc::new(Metrics::default()),
+        meters: Arc::new(Meters::default()),
+        clock,
+        shutdown_tx,
+        _running: running_tx,
+    };
+    let mut completed = shared.sessions.subscribe();
+    tokio::spawn(metrics::record_sessions(shared.sessions.subscribe(), Arc::clone(&shared.metrics), verbose));
+    if let Some(listener) = metrics_listener {
+        let (metrics, sessions) = (Arc::clone(&shared.metrics), shared.sessions.clone());
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, metrics, sessions, verbose).await
+                && verbose
+            {
+                eprintln!("Metrics endpoint failed: {}", e);
+            }
+        });
+    }
+    let mut one_off_session = None;
+    let (mut terminate, mut hangup) = match signals {
+        true => (Terminate::new(), Hangup::new()),
+        false => (Terminate::never(), Hangup::never()),
+    };
+
+    let server = RunningServer {
+        description,
+        local_addr: acceptor.local_addr(),
+        quic_addr,
+        metrics_addr,
+        sessions: shared.sessions.clone(),
+        meters: Arc::clone(&shared.meters),
+        shutdown_tx: shared.shutdown_tx.clone(),
+        task: tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Ok((socket, peer)) = acceptor.accept() => {
+                        if verbose {
+                            println!("Accepted {} connection from {}", peer.transport, peer.addr);
                         }
-                    }
+                        let Peer { addr, probe, .. } = peer;
+                        let shared = shared.clone();
+                        let tls_config = tls_config.clone();
 
-                    let mut socket = reader.into_inner();
+                        tokio::spawn(async move {
+                            let socket = match &tls_config {
+                                Some(config) => match tls::accept(socket, config).await {
+                                    Ok((socket, info)) => {
+                                        if verbose {
+                                            println!(
+                                                "TLS handshake with {} in {} ms ({}, {})",
+                                                addr,
+                                                format_number(info.handshake.as_secs_f64() * 1000.0, &Locale::de),
+                                                info.version,
+                                                info.cipher_suite
+                                            );
+                                        }
+                                        socket
+                                    }
+                                    Err(e) => {
+                                        if verbose {
+                                            eprintln!("TLS handshake with {} failed: {}", addr, e);
+                                        }
+                                        return;
+                                    }
+                                },
+                                None => socket,
+                            };
 
-                    let mut buf = vec![0u8; block_size_kb * 1024];
-                    let mut local_bytes = 0;
-                    let deadline = Instant::now() + Duration::from_secs(duration_secs);
-                    let start = Instant::now();
+                            handle_connection(socket, addr, probe, shared).await;
+                        });
+                    }
 
-                    match mode {
-                        Direction::Upload => {
-                            while let Ok(n) = socket.read(&mut buf).await {
-                                if n == 0 { break; }
-                                local_bytes += n;
-                            }
+                    Some(incoming) = accept_quic(quic_endpoint.as_ref()) => {
+                        tokio::spawn(serve_quic_connection(incoming, shared.clone()));
+                    }
+
+                    Ok(summary) = completed.recv(), if one_off => {
+                        if verbose {
+                            println!("Session {} ended, exiting (--one-off)", summary.id);
                         }
-                        Direction::Download => {
-                            while Instant::now() < deadline {
-                                if socket.write_all(&buf).await.is_err() { break; }
-                                local_bytes += buf.len();
-                            }
+                        one_off_session = Some(summary);
+                        break;
+                    }
+
+                    _ = terminate.recv() => {
+                        if verbose {
+                            println!("Termination signal received. Exiting server loop.");
                         }
-                        Direction::Bidirectional => {
-                            while Instant::now() < deadline {
-                                if socket.write_all(&buf).await.is_err() { break; }
-                                local_bytes += buf.len();
-                                if let Ok(n) = socket.read(&mut buf).await {
-                                    if n == 0 { break; }
-                                    local_bytes += n;
+                        break;
+                    }
+
+                    _ = hangup.recv() => {
+                        match load_settings() {
+                            Ok(settings) => {
+                                tls_config = settings.tls_config;
+                                if let (Some(endpoint), Some(config)) = (&quic_endpoint, settings.quic_config) {
+                                    endpoint.set_server_config(Some(config));
+                                }
+                                *shared.auth.write().unwrap() = settings.auth;
+                                if verbose {
+                                    println!("Settings reloaded");
                                 }
                             }
+                            Err(e) if verbose => eprintln!("Failed to reload settings, keeping the current ones: {}", e),
+                            Err(_) => {}
                         }
-                        _ => {}
                     }
 
-                    {
-                        let duration = start.elapsed();
-                        let mut total_duration = total_duration.lock().await;
-                        *total_duration += duration;
+                    changed = shutdown_rx.changed() => {
+                        if changed.is_ok() && *shutdown_rx.borrow() {
+                            if verbose {
+                                println!("Shutdown signal received. Exiting server loop.");
+                            }
+                            break;
+                        }
                     }
+                }
+            }
 
-                    bytes.fetch_add(local_bytes, Ordering::Relaxed);
-                    println!(
-                        "Client {} disconnected ({} MB)",
-                        addr,
-                        format_number(local_bytes as f64 / 1_000_000.0, &Locale::de)
-                    );
+            // Stop accepting, then give running streams time to finish
+            drop(acceptor);
+            if let Some(endpoint) = &quic_endpoint {
+                endpoint.set_server_config(None);
+            }
+            let _ = shared.shutdown_tx.send(true);
+            let metrics = Arc::clone(&shared.metrics);
+            drop(shared);
 
-                    let mut count = clients.lock().await;
-                    *count += 1;
-                });
+            tokio::select! {
+                _ = running_rx.recv() => {}
+                _ = tokio::time::sleep(drain_timeout) => if verbose {
+                    eprintln!("Streams still running after {} s, stopping anyway", drain_timeout.as_secs());
+                },
+                _ = terminate.recv() => if verbose {
+                    eprintln!("Second termination signal, stopping immediately");
+                },
             }
 
-            changed = quit_rx.changed() => {
-                if changed.is_ok() && *quit_rx.borrow() {
-                    println!("Shutdown signal received. Exiting server loop.");
-                    break;
-                }
+            if let Some(endpoint) = &quic_endpoint {
+                // Clients close their connection once all data arrived, closing first would discard the rest
+                let _ = timeout(QUIC_CLOSE_GRACE, endpoint.wait_idle()).await;
+                endpoint.close(quinn::VarInt::from_u32(0), b"shutdown");
+            }
+
+            ServerReport {
+                session: one_off_session,
+                statistics: Statistics::new(metrics.total_stream_duration().as_secs_f64(), metrics.total_bytes()),
+                one_off,
+            }
+        }),
+    };
+    Ok(server)
+}
+
+async fn accept_quic(endpoint: Option<&quinn::Endpoint>) -> Option<quinn::Incoming> {
+    match endpoint {
+        Some(endpoint) => endpoint.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Every bidirectional stream of a QUIC connection is handled like a separate TCP connection.
+async fn serve_quic_connection(incoming: quinn::Incoming, shared: Shared) {
+    let remote = incoming.remote_address();
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            if shared.verbose {
+                eprintln!("QUIC handshake with {} failed: {}", remote, e);
             }
+            return;
         }
+    };
+    if shared.verbose {
+        println!("Accepted QUIC connection from {}", remote);
     }
 
-    // Statistic
-    let total = total_bytes.load(Ordering::Relaxed);
-    let duration = total_duration.lock().await.as_secs_f64();
+    let mut shutdown = shared.shutdown_tx.subscribe();
+    loop {
+        let (send, recv) = tokio::select! {
+            Ok(stream) = connection.accept_bi() => stream,
+            // Running streams keep their own handle on the connection
+            _ = stopping(&mut shutdown) => break,
+            else => break,
+        };
+        let addr = format!("{}/{}", remote, send.id().index());
+        let stopped = send.stopped();
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            handle_connection(Box::new(tokio::io::join(recv, send)), addr, None, shared).await;
+            // Keeps the connection until the client has all data, dropping the last handle would discard the rest
+            let _ = timeout(QUIC_CLOSE_GRACE, stopped).await;
+        });
+    }
+}
 
-    println!("\n[ERGEBNIS]");
-    print_statistics_terminal(duration, total);
+async fn handle_connection(mut socket: BoxStream, addr: String, probe: Option<SocketProbe>, shared: Shared) {
+    let (request, text) = match read_request(&mut socket).await {
+        Ok(request) => request,
+        Err(reason) => {
+            if shared.verbose {
+                eprintln!("Invalid request from {}: {}", addr, reason);
+            }
+            shared.metrics.rejected(Rejection::Invalid);
+            let _ = send_reply(&mut socket, &Reply::Error(reason)).await;
+            return;
+        }
+    };
+    let mode = request.direction;
+
+    // Quit and run are administrative commands and may use their own key
+    let auth = shared.auth.read().unwrap().clone();
+    let key = match mode {
+        _ if request.run.is_some() => auth.admin_key(),
+        Direction::Quit => auth.admin_key(),
+        _ => auth.session.as_ref(),
+    };
+    if let Some(key) = key
+        && let Err(reason) = authenticate(&mut socket, key, &text).await
+    {
+        if shared.verbose {
+            eprintln!("Rejected {:?} request from {}: {}", mode, addr, reason);
+        }
+        shared.metrics.rejected(Rejection::Auth);
+        let _ = send_reply(&mut socket, &Reply::Error(reason)).await;
+        return;
+    }
+
+    if request.run.is_some()
+        && let Some(reason) = agent_refusal(&shared, &auth)
+    {
+        if shared.verbose {
+            eprintln!("Rejected run command from {}: {}", addr, reason);
+        }
+        shared.metrics.rejected(Rejection::Invalid);
+        let _ = send_reply(&mut socket, &Reply::Error(reason.to_string())).await;
+        return;
+    }
+
+    if mode == Direction::Quit {
+        let _ = send_reply(&mut socket, &Reply::Ok).await;
+        if shared.verbose {
+            println!("Quit signal received from {}", addr);
+        }
+        let _ = shared.shutdown_tx.send(true);
+        return;
+    }
+
+    // Held until the stream ends, so the session counts as running until then. The test of an
+    // agent counts as one stream of the agent's session, its streams go to the target.
+    let joining = match request.run {
+        Some(_) => Request { streams: 1, ..request.clone() },
+        None => request.clone(),
+    };
+    let mut session = loop {
+        let (reply, rejection) = if *shared.shutdown_tx.borrow() {
+            (Reply::Error("server shutting down".to_string()), Rejection::Shutdown)
+        } else {
+            match shared.limits.check(&request).map_err(Reply::Error).and_then(|_| shared.sessions.join(&joining, &shared.limits)) {
+                Ok(guard) => break guard,
+                Err(reply @ Reply::Busy { .. }) => (reply, Rejection::Busy),
+                Err(reply) => (reply, Rejection::Limit),
+            }
+        };
+
+        match &reply {
+            Reply::Busy { position, wait_secs } if shared.verbose => println!("Session of {} queued at position {} (about {} s)", addr, position, wait_secs),
+            Reply::Error(reason) if shared.verbose => eprintln!("Rejected {:?} request from {}: {}", mode, addr, reason),
+            _ => {}
+        }
+        let busy = matches!(reply, Reply::Busy { .. });
+        if send_reply(&mut socket, &reply).await.is_err() || !busy || !request.wait || !wait_turn(&mut socket, &shared).await {
+            // Waiting clients only count as turned away once they give up
+            shared.metrics.rejected(rejection);
+            shared.sessions.leave_queue(&request.session);
+            return;
+        }
+    };
+
+    if send_reply(&mut socket, &Reply::Ok).await.is_err() {
+        if shared.verbose {
+            eprintln!("Failed to confirm request of client {}", addr);
+        }
+        return;
+    }
+
+    if let Some(run) = &request.run {
+        if shared.verbose {
+            println!("Controller {} asked for a {:?} test against {}", addr, request.direction, run.target);
+        }
+        let (line, bytes) = match agent::run_test(&request, run, auth.session.clone(), &shared.agent_verification).await {
+            Ok(Some(measurement)) => (agent::result_line(&measurement), measurement.bytes),
+            Ok(None) => (format!("error test against {} failed\n", run.target), 0),
+            Err(e) => (format!("error {}\n", e), 0),
+        };
+        session.record(bytes, bytes > 0);
+        let _ = socket.write_all(line.as_bytes()).await;
+        let _ = socket.shutdown().await;
+        return;
+    }
+
+    shared.metrics.stream_started();
+    let meter = shared.meters.register(format!("{} {:?}", addr, mode), probe);
+    let start = shared.clock.now();
+    let test = StreamTest {
+        role: Role::Server,
+        direction: mode,
+        block_size: shared.block_size_kb * 1024,
+        deadline: start + Duration::from_secs(request.duration_secs),
+        limiter: shared.limits.bandwidth.as_deref(),
+        clock: shared.clock.as_ref(),
+    };
+    let Transfer { bytes: local_bytes, ok } = run_stream(&mut socket, &test, &meter).await;
+    session.record(local_bytes, ok);
+
+    shared.metrics.stream_finished(mode, local_bytes, shared.clock.now() - start);
+    if shared.verbose {
+        println!("Client {} disconnected ({} MB)", addr, format_number(local_bytes as f64 / 1_000_000.0, &Locale::de));
+    }
+}
+
+/// Why a run command is refused, if it is. Reloaded settings may have dropped the keys, so an
+/// agent without one refuses here as well as at startup.
+fn agent_refusal(shared: &Shared, auth: &ServerAuth) -> Option<&'static str> {
+    match (shared.agent, auth.admin_key()) {
+        (false, _) => Some("agent mode is disabled (--agent)"),
+        (true, None) => Some("agent mode requires a key"),
+        (true, Some(_)) => None,
+    }
+}
+
+/// Keeps a queued client until another session ends or it is time for a position update.
+/// Returns false if the client gave up and closed the connection or the server shuts down.
+async fn wait_turn(socket: &mut BoxStream, shared: &Shared) -> bool {
+    let mut probe = [0u8; 1];
+    let mut shutdown = shared.shutdown_tx.subscribe();
+    tokio::select! {
+        _ = shared.sessions.ended() => true,
+        _ = tokio::time::sleep(QUEUE_UPDATE) => true,
+        // A waiting client sends nothing, so any read result means it is gone
+        _ = socket.read(&mut probe) => false,
+        _ = stopping(&mut shutdown) => {
+            let _ = send_reply(socket, &Reply::Error("server shutting down".to_string())).await;
+            false
+        }
+    }
+}
+
+/// Resolves once the server shuts down.
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// Challenge-response with the pre-shared key. Returns the reason for the client on failure.
+async fn authenticate(socket: &mut BoxStream, key: &Key, request: &str) -> Result<(), String> {
+    let nonce = auth::new_nonce().map_err(|e| e.to_string())?;
+    send_reply(socket, &Reply::Challenge(nonce.clone())).await.map_err(|e| e.to_string())?;
+
+    let line = match timeout(AUTH_TIMEOUT, read_line(socket)).await {
+        Ok(Ok(line)) => line,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("authentication timed out".to_string()),
+    };
+
+    match line.strip_prefix("response ") {
+        Some(response) if auth::verify(key, &nonce, request, response) => Ok(()),
+        _ => Err("authentication failed".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, net::ConnectOptions};
+
+    #[tokio::test]
+    async fn test_embedded() {
+        let key = Key::new(b"secret");
+        let auth = ServerAuth {
+            session: Some(key.clone()),
+            admin: None,
+        };
+        let server = Server::new().port(0).auth(auth).one_off(true).start().await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+
+        let client = Client::new(address).streams(2).block_size_kb(16).duration_secs(1).direction(Direction::Download);
+        assert!(client.run().await.is_err(), "the server requires a key");
+
+        let options = ConnectOptions {
+            psk: Some(key),
+            ..ConnectOptions::default()
+        };
+        let result = client.connect_options(options).run().await.unwrap();
+        assert_eq!(result.measurement.errors, 0);
+        assert_eq!(result.measurement.stream_bytes.len(), 2);
+        assert_eq!(result.statistics.total_bytes, result.measurement.bytes);
+
+        let report = server.wait().await;
+        assert!(report.succeeded());
+        assert_eq!(report.session.unwrap().joined, 2);
+    }
 }
@@ -0,0 +1,87 @@
+#[cfg(unix)]
+use tokio::signal::unix::{Signal, SignalKind, signal};
+
+/// SIGINT or SIGTERM, only Ctrl-C on platforms without Unix signals.
+pub struct Terminate {
+    enabled: bool,
+    #[cfg(unix)]
+    sigterm: Option<Signal>,
+}
+
+impl Default for Terminate {
+    fn default() -> Self {
+        Terminate::new()
+    }
+}
+
+impl Terminate {
+    pub fn new() -> Self {
+        Terminate {
+            enabled: true,
+            #[cfg(unix)]
+            sigterm: Some(signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM")),
+        }
+    }
+
+    /// Never fires, for servers embedded in a program that handles signals itself.
+    pub fn never() -> Self {
+        Terminate {
+            enabled: false,
+            #[cfg(unix)]
+            sigterm: None,
+        }
+    }
+
+    pub async fn recv(&mut self) {
+        if !self.enabled {
+            return std::future::pending().await;
+        }
+        #[cfg(unix)]
+        if let Some(sigterm) = &mut self.sigterm {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// SIGHUP, never fires on platforms without Unix signals.
+pub struct Hangup {
+    #[cfg(unix)]
+    sighup: Option<Signal>,
+}
+
+impl Default for Hangup {
+    fn default() -> Self {
+        Hangup::new()
+    }
+}
+
+impl Hangup {
+    pub fn new() -> Self {
+        Hangup {
+            #[cfg(unix)]
+            sighup: Some(signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP")),
+        }
+    }
+
+    /// Never fires, see [`Terminate::never`].
+    pub fn never() -> Self {
+        Hangup {
+            #[cfg(unix)]
+            sighup: None,
+        }
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(sighup) = &mut self.sighup {
+            sighup.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await;
+    }
+}
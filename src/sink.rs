@@ -0,0 +1,170 @@
+use chrono::{DateTime, Local};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::Direction;
+use crate::history::Store;
+use crate::utils::{Statistics, append_statistics_csv};
+
+/// One result of a network or file test.
+#[derive(Clone, Debug)]
+pub struct Measurement {
+    pub timestamp: DateTime<Local>,
+    /// Server address, or the file path of a file test.
+    pub target: String,
+    pub direction: Direction,
+    pub block_size_kb: usize,
+    pub duration_secs: f64,
+    pub bytes: usize,
+    /// Mean connect round trip of the streams, none for file tests.
+    pub latency_ms: Option<f64>,
+    /// Streams that could not be opened or broke off.
+    pub errors: usize,
+    /// Bytes per stream in stream order, empty for file tests and imported results.
+    pub stream_bytes: Vec<usize>,
+}
+
+#[cfg(test)]
+impl Measurement {
+    /// A test of `mbit` MBit/s over 1 s against 127.0.0.1:4000, for tests to override fields of.
+    pub(crate) fn example(direction: Direction, mbit: f64) -> Measurement {
+        Measurement {
+            timestamp: Local::now(),
+            target: "127.0.0.1:4000".to_string(),
+            direction,
+            block_size_kb: 64,
+            duration_secs: 1.0,
+            bytes: (mbit * 125_000.0) as usize,
+            latency_ms: None,
+            errors: 0,
+            stream_bytes: Vec::new(),
+        }
+    }
+}
+
+impl Measurement {
+    pub fn statistics(&self) -> Statistics {
+        Statistics::new(self.duration_secs, self.bytes)
+    }
+
+    pub fn mbit_per_sec(&self) -> f64 {
+        self.bytes as f64 * 8.0 / 1_000_000.0 / self.duration_secs
+    }
+
+    /// File tests write (upload) or read (download) `bytes` at `target` and have no block size.
+    pub fn is_file_test(&self) -> bool {
+        self.block_size_kb == 0
+    }
+
+    /// Throughput of each stream over the whole test.
+    pub fn stream_mbit_per_sec(&self) -> Vec<f64> {
+        self.stream_bytes.iter().map(|bytes| *bytes as f64 * 8.0 / 1_000_000.0 / self.duration_secs).collect()
+    }
+}
+
+/// Where results are written, given as `csv:PATH`, `jsonl:PATH` or `sqlite:PATH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sink {
+    /// Semicolon separated with German number format, like `results.csv`.
+    Csv(PathBuf),
+    /// One JSON object per line.
+    Jsonl(PathBuf),
+    /// Database queried by the `history` command.
+    Sqlite(PathBuf),
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("csv", path)) if !path.is_empty() => Ok(Sink::Csv(PathBuf::from(path))),
+            Some(("jsonl", path)) if !path.is_empty() => Ok(Sink::Jsonl(PathBuf::from(path))),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Sink::Sqlite(PathBuf::from(path))),
+            _ => Err(format!("Invalid sink '{}', expected csv:PATH, jsonl:PATH or sqlite:PATH", s)),
+        }
+    }
+}
+
+impl Sink {
+    pub fn write(&self, measurement: &Measurement) -> io::Result<()> {
+        match self {
+            Sink::Csv(path) => append_statistics_csv(
+                path,
+                measurement.timestamp,
+                measurement.duration_secs,
+                measurement.bytes,
+                measurement.direction,
+                measurement.block_size_kb,
+                &measurement.target,
+            ),
+            Sink::Jsonl(path) => {
+                let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+                writeln!(file, "{}", to_json(measurement))
+            }
+            Sink::Sqlite(path) => Store::open(path).and_then(|store| store.insert(measurement)).map(|_| ()).map_err(io::Error::other),
+        }
+    }
+}
+
+pub fn to_json(measurement: &Measurement) -> String {
+    format!(
+        "{{\"timestamp\":\"{}\",\"target\":{},\"direction\":\"{:?}\",\"block_size_kb\":{},\"duration_secs\":{},\"bytes\":{},\"mbit_per_sec\":{},\"latency_ms\":{},\"errors\":{},\"streams\":[{}]}}",
+        measurement.timestamp.to_rfc3339(),
+        json_string(&measurement.target),
+        measurement.direction,
+        measurement.block_size_kb,
+        measurement.duration_secs,
+        measurement.bytes,
+        json_number(measurement.mbit_per_sec()),
+        measurement.latency_ms.map_or("null".to_string(), json_number),
+        measurement.errors,
+        measurement.stream_bytes.iter().map(|bytes| bytes.to_string()).collect::<Vec<_>>().join(",")
+    )
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON has no NaN or infinity, e.g. for tests that transferred nothing in no time.
+pub fn json_number(value: f64) -> String {
+    match value.is_finite() {
+        true => value.to_string(),
+        false => "null".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sink_and_json() {
+        assert_eq!("jsonl:/tmp/r.jsonl".parse::<Sink>(), Ok(Sink::Jsonl(PathBuf::from("/tmp/r.jsonl"))));
+        assert!("csv:".parse::<Sink>().is_err());
+        assert!("results.csv".parse::<Sink>().is_err());
+
+        let measurement = Measurement {
+            target: "unix:/tmp/\"x\"".to_string(),
+            errors: 1,
+            stream_bytes: vec![6_250_000; 2],
+            ..Measurement::example(Direction::Download, 100.0)
+        };
+        let json = to_json(&measurement);
+        assert!(json.contains("\"target\":\"unix:/tmp/\\\"x\\\"\""));
+        assert!(json.ends_with("\"mbit_per_sec\":100,\"latency_ms\":null,\"errors\":1,\"streams\":[6250000,6250000]}"));
+    }
+}
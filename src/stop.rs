@@ -0,0 +1,39 @@
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::time::{Duration, sleep};
+
+/// For commands running one test after another: the first SIGINT or SIGTERM sets the returned flag,
+/// so the running test can finish, a second one exits immediately.
+pub fn stop_after_test() -> watch::Receiver<bool> {
+    let (stop_tx, stop) = watch::channel(false);
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        for signals in 1.. {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+
+            if signals > 1 {
+                std::process::exit(130);
+            }
+            println!("Stopping after the running test, signal again to abort");
+            let _ = stop_tx.send(true);
+        }
+    });
+    stop
+}
+
+/// Sleeps unless a stop is requested. Returns false on stop.
+pub async fn pause(duration: Duration, stop: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        biased;
+        _ = stop.wait_for(|stop| *stop) => false,
+        _ = sleep(duration) => true,
+    }
+}
@@ -0,0 +1,274 @@
+use num_format::Locale;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use tokio::time::Duration;
+
+use speedtest::{
+    Direction, client,
+    net::ConnectOptions,
+    sink::{Measurement, Sink},
+    utils::format_number,
+};
+
+use crate::stop::{pause, stop_after_test};
+
+/// Upper bound for the values of one dimension, against ranges like `1..1000000`.
+const MAX_STEPS: usize = 100;
+
+/// Values of one sweep dimension, comma separated. Each item is a number or an inclusive range:
+/// `1..8` counts up by one, `1..64*2` doubles, `10..30+10` adds 10.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Steps(pub Vec<u64>);
+
+impl FromStr for Steps {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |value: &str| value.trim().parse::<u64>().map_err(|_| format!("Invalid number '{}' in '{}'", value, s));
+        let mut values = Vec::new();
+
+        for item in s.split(',') {
+            let Some((start, rest)) = item.split_once("..") else {
+                values.push(number(item)?);
+                continue;
+            };
+            let start = number(start)?;
+            let (end, next): (u64, Box<dyn Fn(u64) -> Option<u64>>) = match (rest.split_once('*'), rest.split_once('+')) {
+                (Some((end, factor)), _) => match number(factor)? {
+                    factor if factor >= 2 => (number(end)?, Box::new(move |value| value.checked_mul(factor))),
+                    _ => return Err(format!("Factor in '{}' must be at least 2", item)),
+                },
+                (_, Some((end, step))) => match number(step)? {
+                    step if step >= 1 => (number(end)?, Box::new(move |value| value.checked_add(step))),
+                    _ => return Err(format!("Step in '{}' must be at least 1", item)),
+                },
+                _ => (number(rest)?, Box::new(|value| value.checked_add(1))),
+            };
+            if start > end {
+                return Err(format!("Range '{}' is empty", item));
+            }
+
+            // The range ends early where the next value would overflow, e.g. at u64::MAX
+            let mut value = Some(start);
+            while let Some(current) = value.filter(|value| *value <= end && values.len() <= MAX_STEPS) {
+                values.push(current);
+                value = next(current);
+            }
+        }
+
+        if values.contains(&0) {
+            return Err(format!("Values in '{}' must be at least 1", s));
+        }
+        if values.len() > MAX_STEPS {
+            return Err(format!("More than {} values in '{}'", MAX_STEPS, s));
+        }
+        Ok(Steps(values))
+    }
+}
+
+pub struct SweepOptions {
+    pub address: String,
+    pub connect: ConnectOptions,
+    pub threads: Steps,
+    pub block_sizes_kb: Steps,
+    pub durations_secs: Steps,
+    pub directions: Vec<Direction>,
+    /// Pause between two runs, so one run's connections are gone before the next starts.
+    pub pause: Duration,
+    pub sinks: Vec<Sink>,
+}
+
+/// One combination of the sweep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Combination {
+    direction: Direction,
+    duration_secs: u64,
+    threads: usize,
+    block_size_kb: usize,
+}
+
+/// Runs every combination of the options one after another and prints a comparison table.
+/// A signal stops the sweep after the running test, the table then covers the runs so far.
+pub async fn run_sweep(options: SweepOptions) {
+    let combinations = combinations(&options);
+    let total_secs: u64 = combinations.iter().map(|combination| combination.duration_secs).sum();
+    println!("Sweeping {} combinations, at least {} s of tests", combinations.len(), total_secs);
+
+    let mut stop = stop_after_test();
+    let mut results = Vec::new();
+    for (index, combination) in combinations.iter().enumerate() {
+        if index > 0 && !pause(options.pause, &mut stop).await {
+            break;
+        }
+
+        println!(
+            "\n[SWEEP {}/{}] {:?}, {} Streams, {} KiB, {} s",
+            index + 1,
+            combinations.len(),
+            combination.direction,
+            combination.threads,
+            combination.block_size_kb,
+            combination.duration_secs
+        );
+        let measurement = client::run_client(
+            options.address.clone(),
+            options.connect.clone(),
+            combination.threads,
+            combination.block_size_kb,
+            combination.duration_secs,
+            combination.direction,
+            false,
+        )
+        .await;
+
+        if let Some(measurement) = &measurement {
+            for sink in &options.sinks {
+                if let Err(e) = sink.write(measurement) {
+                    eprintln!("Failed to write result to {:?}: {}", sink, e);
+                }
+            }
+        }
+        results.push((*combination, measurement));
+
+        if *stop.borrow() {
+            break;
+        }
+    }
+
+    print_table(&results);
+}
+
+/// The Cartesian product, grouped by direction so the table compares like with like.
+fn combinations(options: &SweepOptions) -> Vec<Combination> {
+    let mut combinations = Vec::new();
+    for &direction in &options.directions {
+        for &duration_secs in &options.durations_secs.0 {
+            for &threads in &options.threads.0 {
+                for &block_size_kb in &options.block_sizes_kb.0 {
+                    combinations.push(Combination {
+                        direction,
+                        duration_secs,
+                        threads: threads as usize,
+                        block_size_kb: block_size_kb as usize,
+                    });
+                }
+            }
+        }
+    }
+    combinations
+}
+
+/// Index of the fastest run per direction. Runs with failed streams do not count.
+fn best(results: &[(Combination, Option<Measurement>)]) -> BTreeMap<Direction, usize> {
+    let mut best: BTreeMap<Direction, usize> = BTreeMap::new();
+    for (index, (combination, measurement)) in results.iter().enumerate() {
+        let Some(measurement) = measurement.as_ref().filter(|measurement| measurement.errors == 0 && measurement.bytes > 0) else {
+            continue;
+        };
+        let faster = |current: &usize| results[*current].1.as_ref().is_none_or(|current| measurement.mbit_per_sec() > current.mbit_per_sec());
+        if best.get(&combination.direction).is_none_or(faster) {
+            best.insert(combination.direction, index);
+        }
+    }
+    best
+}
+
+fn print_table(results: &[(Combination, Option<Measurement>)]) {
+    let locale = Locale::de;
+    let best = best(results);
+
+    println!("\n[ERGEBNIS]");
+    println!(
+        "  {:<13}  {:>7}  {:>9}  {:>7}  {:>12}  {:>10}  {:>6}",
+        "Richtung", "Streams", "Block KiB", "Dauer s", "MBit/s", "Latenz ms", "Fehler"
+    );
+    for (index, (combination, measurement)) in results.iter().enumerate() {
+        let marker = if best.values().any(|best| *best == index) { '*' } else { ' ' };
+        let (mbit, latency, errors) = match measurement {
+            Some(measurement) => (
+                format_number(measurement.mbit_per_sec(), &locale),
+                measurement.latency_ms.map_or("-".to_string(), |latency| format_number(latency, &locale)),
+                measurement.errors.to_string(),
+            ),
+            None => ("-".to_string(), "-".to_string(), "alle".to_string()),
+        };
+        println!(
+            "{} {:<13}  {:>7}  {:>9}  {:>7}  {:>12}  {:>10}  {:>6}",
+            marker,
+            format!("{:?}", combination.direction),
+            combination.threads,
+            combination.block_size_kb,
+            combination.duration_secs,
+            mbit,
+            latency,
+            errors
+        );
+    }
+
+    for (direction, index) in &best {
+        let (combination, measurement) = &results[*index];
+        if let Some(measurement) = measurement {
+            println!(
+                "* Beste Kombination {:?}: {} Streams, {} KiB Blöcke mit {} MBit/s",
+                direction,
+                combination.threads,
+                combination.block_size_kb,
+                format_number(measurement.mbit_per_sec(), &locale)
+            );
+        }
+    }
+    if best.is_empty() {
+        println!("Kein Lauf ohne Fehler");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+
+    #[test]
+    fn test_steps_and_best() {
+        assert_eq!("1,2,4".parse(), Ok(Steps(vec![1, 2, 4])));
+        assert_eq!("1..4".parse(), Ok(Steps(vec![1, 2, 3, 4])));
+        assert_eq!("16..256*2".parse(), Ok(Steps(vec![16, 32, 64, 128, 256])));
+        assert_eq!("10..35+10,60".parse(), Ok(Steps(vec![10, 20, 30, 60])));
+        assert!("0..4".parse::<Steps>().is_err());
+        assert!("4..1".parse::<Steps>().is_err());
+        assert!("1..1000000".parse::<Steps>().is_err());
+        assert!("1..8*1".parse::<Steps>().is_err());
+        let max = u64::MAX;
+        assert_eq!(format!("{}..{}", max - 1, max).parse(), Ok(Steps(vec![max - 1, max])));
+        assert_eq!(format!("{}..{}*2", max / 2 + 1, max).parse(), Ok(Steps(vec![max / 2 + 1])));
+        assert_eq!(format!("{}..{}+3", max - 4, max).parse(), Ok(Steps(vec![max - 4, max - 1])));
+
+        let run = |direction, threads, mbit: f64, errors| {
+            let combination = Combination {
+                direction,
+                duration_secs: 1,
+                threads,
+                block_size_kb: 64,
+            };
+            let measurement = Measurement {
+                timestamp: Local::now(),
+                target: "127.0.0.1:4000".to_string(),
+                direction,
+                block_size_kb: 64,
+                duration_secs: 1.0,
+                bytes: (mbit * 125_000.0) as usize,
+                latency_ms: None,
+                errors,
+                stream_bytes: Vec::new(),
+            };
+            (combination, Some(measurement))
+        };
+        let mut results = vec![
+            run(Direction::Download, 1, 100.0, 0),
+            run(Direction::Download, 2, 300.0, 1),
+            run(Direction::Download, 4, 200.0, 0),
+            run(Direction::Upload, 1, 50.0, 0),
+        ];
+        results.push((Combination { threads: 8, ..results[3].0 }, None));
+        assert_eq!(best(&results), BTreeMap::from([(Direction::Upload, 3), (Direction::Download, 2)]));
+    }
+}
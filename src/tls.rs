@@ -0,0 +1,194 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms, ring};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::Duration;
+use tokio_rustls::{TlsAcceptor, TlsConnector, rustls};
+
+use crate::net::BoxStream;
+
+/// Outcome of a TLS handshake on one stream.
+#[derive(Clone, Debug)]
+pub struct TlsInfo {
+    pub handshake: Duration,
+    pub cipher_suite: String,
+    pub version: String,
+}
+
+/// Client side TLS settings shared by all streams of a test.
+#[derive(Clone, Debug)]
+pub struct TlsClientOptions {
+    pub config: Arc<ClientConfig>,
+    pub server_name: Option<String>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub fn invalid_input(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// How the client checks the server certificate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Verification {
+    /// Against the bundled web roots, for servers with a publicly trusted certificate.
+    #[default]
+    WebRoots,
+    /// Against the CA certificates in this PEM file.
+    Ca(PathBuf),
+    /// Not at all, e.g. for servers with a certificate generated on the fly. Only on request.
+    Insecure,
+}
+
+pub fn client_config(verification: &Verification) -> io::Result<Arc<ClientConfig>> {
+    build_client_config(verification).map(Arc::new)
+}
+
+pub fn build_client_config(verification: &Verification) -> io::Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions().map_err(invalid_input)?;
+
+    let config = match verification {
+        Verification::WebRoots => builder.with_root_certificates(web_roots()).with_no_client_auth(),
+        Verification::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path).map_err(invalid_input)? {
+                roots.add(cert.map_err(invalid_input)?).map_err(invalid_input)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Verification::Insecure => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider().signature_verification_algorithms)))
+            .with_no_client_auth(),
+    };
+
+    Ok(config)
+}
+
+fn web_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// Configuration for public HTTPS endpoints such as alert webhooks, verified against the bundled web roots.
+pub fn web_client_config() -> io::Result<Arc<ClientConfig>> {
+    client_config(&Verification::WebRoots)
+}
+
+/// Certificate chain and private key of the server, shared by TLS and QUIC.
+#[derive(Debug)]
+pub struct Identity {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl Clone for Identity {
+    fn clone(&self) -> Self {
+        Identity {
+            certs: self.certs.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+impl Identity {
+    /// Reads the certificate chain and key from PEM files.
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Identity> {
+        let certs = CertificateDer::pem_file_iter(cert).map_err(invalid_input)?.collect::<Result<Vec<_>, _>>().map_err(invalid_input)?;
+        Ok(Identity {
+            certs,
+            key: PrivateKeyDer::from_pem_file(key).map_err(invalid_input)?,
+        })
+    }
+
+    /// Generates a self-signed certificate for localhost. Generate it once per server, clients
+    /// that pinned it (--tls-ca) fail with every new one.
+    pub fn generate() -> io::Result<Identity> {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).map_err(invalid_input)?;
+        Ok(Identity {
+            certs: vec![generated.cert.der().clone()],
+            key: PrivateKeyDer::Pkcs8(generated.key_pair.serialize_der().into()),
+        })
+    }
+}
+
+pub fn server_config(identity: &Identity) -> io::Result<Arc<ServerConfig>> {
+    build_server_config(identity).map(Arc::new)
+}
+
+pub fn build_server_config(identity: &Identity) -> io::Result<ServerConfig> {
+    ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_input)?
+        .with_no_client_auth()
+        .with_single_cert(identity.certs.clone(), identity.key.clone_key())
+        .map_err(invalid_input)
+}
+
+/// Name the server certificate is checked against: the override if given, else the host (DNS name or IP).
+pub fn server_name(name: Option<&str>, host: &str) -> io::Result<ServerName<'static>> {
+    let name = name.unwrap_or(host).trim_start_matches('[').trim_end_matches(']').to_string();
+    ServerName::try_from(name).map_err(invalid_input)
+}
+
+/// Runs the client handshake on `stream`. `host` is used for SNI and verification unless overridden.
+pub async fn connect(stream: BoxStream, options: &TlsClientOptions, host: &str) -> io::Result<(BoxStream, TlsInfo)> {
+    let server_name = server_name(options.server_name.as_deref(), host)?;
+
+    let start = Instant::now();
+    let stream = TlsConnector::from(Arc::clone(&options.config)).connect(server_name, stream).await?;
+    let handshake = start.elapsed();
+
+    let (_, session) = stream.get_ref();
+    let info = session_info(handshake, session.negotiated_cipher_suite(), session.protocol_version());
+    Ok((Box::new(stream), info))
+}
+
+/// Runs the server handshake on an accepted `stream`.
+pub async fn accept(stream: BoxStream, config: &Arc<ServerConfig>) -> io::Result<(BoxStream, TlsInfo)> {
+    let start = Instant::now();
+    let stream = TlsAcceptor::from(Arc::clone(config)).accept(stream).await?;
+    let handshake = start.elapsed();
+
+    let (_, session) = stream.get_ref();
+    let info = session_info(handshake, session.negotiated_cipher_suite(), session.protocol_version());
+    Ok((Box::new(stream), info))
+}
+
+fn session_info(handshake: Duration, suite: Option<rustls::SupportedCipherSuite>, version: Option<rustls::ProtocolVersion>) -> TlsInfo {
+    TlsInfo {
+        handshake,
+        cipher_suite: suite.map(|s| format!("{:?}", s.suite())).unwrap_or_else(|| "unknown".to_string()),
+        version: version.map(|v| format!("{:?}", v)).unwrap_or_else(|| "unknown".to_string()),
+    }
+}
+
+/// Skips certificate validation but still checks handshake signatures.
+#[derive(Debug)]
+struct AcceptAnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(&self, _: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
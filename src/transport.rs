@@ -0,0 +1,99 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{DuplexStream, duplex};
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::Duration;
+
+use crate::net::{BoxStream, Connection, Peer};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Client side of a transport: opens the streams of one test, all to the same server.
+/// The returned stream is ready for the protocol handshake, e.g. with TLS already set up.
+pub trait Connector: Send + Sync {
+    /// Opens stream `stream_index` (0-based).
+    fn connect(&self, stream_index: usize) -> BoxFuture<'_, io::Result<Connection>>;
+
+    /// Called once after all streams of the test ended.
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Server side of a transport: yields the streams of all clients. The server runs TLS on top if configured.
+pub trait Acceptor: Send + Sync {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, Peer)>>;
+
+    /// Listen address as logged at startup.
+    fn describe(&self) -> String;
+
+    /// TCP address, `None` for other transports.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Transport within one process over `tokio::io::duplex` pipes of `buffer` bytes, for tests
+/// and for embedding without sockets. Every connect hands one end of a new pipe to the acceptor.
+pub fn memory(buffer: usize) -> (MemoryConnector, MemoryAcceptor) {
+    let (streams_tx, streams) = mpsc::unbounded_channel();
+    let connector = MemoryConnector { buffer, streams_tx };
+    let acceptor = MemoryAcceptor {
+        streams: Mutex::new(streams),
+        accepted: AtomicUsize::new(0),
+    };
+    (connector, acceptor)
+}
+
+#[derive(Clone)]
+pub struct MemoryConnector {
+    buffer: usize,
+    streams_tx: mpsc::UnboundedSender<DuplexStream>,
+}
+
+impl Connector for MemoryConnector {
+    fn connect(&self, stream_index: usize) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            let (stream, server_end) = duplex(self.buffer);
+            self.streams_tx
+                .send(server_end)
+                .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "The in-memory server is gone"))?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                description: format!("in-memory pipe {}", stream_index + 1),
+                tls: None,
+                latency: Duration::ZERO,
+                probe: None,
+            })
+        })
+    }
+}
+
+pub struct MemoryAcceptor {
+    streams: Mutex<mpsc::UnboundedReceiver<DuplexStream>>,
+    accepted: AtomicUsize,
+}
+
+impl Acceptor for MemoryAcceptor {
+    /// Waits forever once all connectors are dropped, like a listener nobody connects to.
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, Peer)>> {
+        Box::pin(async move {
+            let Some(stream) = self.streams.lock().await.recv().await else {
+                return std::future::pending().await;
+            };
+            let peer = Peer {
+                addr: format!("memory#{}", self.accepted.fetch_add(1, Ordering::Relaxed) + 1),
+                transport: "in-memory",
+                probe: None,
+            };
+            Ok((Box::new(stream) as BoxStream, peer))
+        })
+    }
+
+    fn describe(&self) -> String {
+        "in-memory pipes".to_string()
+    }
+}
@@ -0,0 +1,436 @@
+use num_format::Locale;
+use ratatui::{
+    Frame, Terminal,
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph, Sparkline},
+};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::live::{Meter, Meters, TcpInfo};
+use crate::utils::format_number;
+
+/// Throughput is sampled once per interval.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often keys are checked between samples.
+const KEY_POLL: Duration = Duration::from_millis(100);
+
+/// Samples kept per sparkline, more than any terminal is wide.
+const HISTORY: usize = 500;
+
+/// Captured output lines shown below the graphs.
+const LOG_LINES: u16 = 8;
+
+/// Captured output lines kept for printing after the view closes, the oldest go first.
+const KEPT_LINES: usize = 1000;
+
+/// What the header shows besides the title and the elapsed time.
+pub type Status = Box<dyn Fn() -> String + Send>;
+
+/// Full-screen live view of the running streams. While it runs, everything written to stdout and stderr
+/// is captured into its log panel and printed again once the terminal is restored.
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+    /// Takes over the terminal, or returns `None` after a note if stdout is not a terminal.
+    /// `duration` adds the remaining time, counted from the first stream.
+    pub fn start(meters: Arc<Meters>, title: String, duration: Option<Duration>, status: Status) -> Option<Tui> {
+        if !cfg!(unix) || !io::stdout().is_terminal() {
+            eprintln!("Not a terminal, using the plain output instead of --tui");
+            return None;
+        }
+
+        let lines = Arc::new(Mutex::new(Log::default()));
+        let (capture, terminal) = match Capture::start(Arc::clone(&lines)).and_then(|capture| {
+            let terminal = open_terminal(capture.terminal()?)?;
+            Ok((capture, terminal))
+        }) {
+            Ok(started) => started,
+            Err(e) => {
+                eprintln!("Failed to start the terminal UI, using the plain output: {}", e);
+                return None;
+            }
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let view = View {
+            meters,
+            title,
+            duration,
+            status,
+            lines,
+            started: None,
+            streams: Vec::new(),
+            total: VecDeque::new(),
+        };
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || run(view, terminal, capture, &stop)
+        });
+
+        Some(Tui { stop, thread: Some(thread) })
+    }
+
+    /// Restores the terminal and prints the captured output.
+    pub fn stop(mut self) {
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+type Backend = CrosstermBackend<File>;
+
+fn open_terminal(output: File) -> io::Result<Terminal<Backend>> {
+    terminal::enable_raw_mode()?;
+    let mut backend = CrosstermBackend::new(output);
+    execute!(backend, EnterAlternateScreen, cursor::Hide)?;
+    Terminal::new(backend)
+}
+
+fn close_terminal(terminal: &mut Terminal<Backend>) {
+    let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen, cursor::Show);
+    let _ = terminal::disable_raw_mode();
+}
+
+/// Draws until stopped. Ctrl-C, `q` or Esc restore the terminal and then raise SIGINT,
+/// since raw mode turns Ctrl-C into a key press.
+fn run(mut view: View, mut terminal: Terminal<Backend>, capture: Capture, stop: &AtomicBool) {
+    let mut next_sample = Instant::now();
+    let mut interrupted = false;
+
+    while !stop.load(Ordering::Relaxed) {
+        if Instant::now() >= next_sample {
+            view.sample();
+            next_sample += SAMPLE_INTERVAL;
+            let _ = terminal.draw(|frame| view.draw(frame));
+        }
+
+        match event::poll(KEY_POLL).and_then(|ready| if ready { event::read().map(Some) } else { Ok(None) }) {
+            Ok(Some(Event::Key(key))) if is_quit(&key) => {
+                interrupted = true;
+                break;
+            }
+            Ok(Some(Event::Resize(..))) => {
+                let _ = terminal.draw(|frame| view.draw(frame));
+            }
+            _ => {}
+        }
+    }
+
+    close_terminal(&mut terminal);
+    drop(terminal);
+    for line in capture.stop() {
+        println!("{}", line);
+    }
+
+    if interrupted {
+        #[cfg(unix)]
+        unsafe {
+            // SAFETY: raising a signal has no memory safety requirements
+            libc::raise(libc::SIGINT);
+        }
+    }
+}
+
+fn is_quit(key: &KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => true,
+        KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
+
+/// Rates of one stream, in KBit/s so slow links still show in the sparkline.
+#[derive(Default)]
+struct StreamHistory {
+    bytes: usize,
+    rates: VecDeque<u64>,
+    tcp_info: Option<TcpInfo>,
+}
+
+struct View {
+    meters: Arc<Meters>,
+    title: String,
+    duration: Option<Duration>,
+    status: Status,
+    lines: Arc<Mutex<Log>>,
+    /// When the first stream showed up.
+    started: Option<Instant>,
+    /// Running streams in the order they started.
+    streams: Vec<(Arc<Meter>, StreamHistory)>,
+    total: VecDeque<u64>,
+}
+
+impl View {
+    fn sample(&mut self) {
+        let running = self.meters.running();
+        if !running.is_empty() && self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+
+        let mut total = 0;
+        let mut previous = std::mem::take(&mut self.streams);
+        for meter in running {
+            let mut history = match previous.iter().position(|(known, _)| Arc::ptr_eq(known, &meter)) {
+                Some(index) => previous.swap_remove(index).1,
+                None => StreamHistory::default(),
+            };
+            let bytes = meter.bytes();
+            let rate = ((bytes - history.bytes) as f64 * 8.0 / 1_000.0 / SAMPLE_INTERVAL.as_secs_f64()) as u64;
+            history.bytes = bytes;
+            push(&mut history.rates, rate);
+            history.tcp_info = meter.tcp_info();
+            total += rate;
+            self.streams.push((meter, history));
+        }
+
+        push(&mut self.total, total);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, total, list, log] = Layout::vertical([Constraint::Length(3), Constraint::Length(7), Constraint::Min(3), Constraint::Length(LOG_LINES + 2)]).areas(frame.area());
+
+        let elapsed = self.started.map_or(Duration::ZERO, |started| started.elapsed());
+        let mut time = format!("Laufzeit {}", format_time(elapsed));
+        if let Some(duration) = self.duration {
+            time.push_str(&format!(" / {}, verbleibend {}", format_time(duration), format_time(duration.saturating_sub(elapsed))));
+        }
+        let status = (self.status)();
+        let header_text = vec![Line::from(format!("{}  (q beendet)", self.title)), Line::from(format!("{}   {}", time, status))];
+        frame.render_widget(Paragraph::new(header_text).block(Block::default().borders(Borders::BOTTOM)), header);
+
+        let current = self.total.back().copied().unwrap_or(0);
+        let block = Block::default().borders(Borders::ALL).title(format!(" Gesamt {} MBit/s ", format_mbit(current)));
+        frame.render_widget(sparkline(&self.total, block.inner(total).width).block(block).style(Style::default().fg(Color::Green)), total);
+
+        let block = Block::default().borders(Borders::ALL).title(format!(" Streams ({}) ", self.streams.len()));
+        let inner = block.inner(list);
+        frame.render_widget(block, list);
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.streams.len()]).split(inner);
+        for ((meter, history), row) in self.streams.iter().zip(rows.iter()) {
+            draw_stream(frame, *row, meter, history);
+        }
+
+        let lines = self.lines.lock().unwrap();
+        let shown: Vec<Line> = lines.last(LOG_LINES as usize).map(|line| Line::from(line.as_str())).collect();
+        frame.render_widget(Paragraph::new(shown).block(Block::default().borders(Borders::ALL).title(" Ausgabe ")), log);
+    }
+}
+
+fn draw_stream(frame: &mut Frame, row: Rect, meter: &Meter, history: &StreamHistory) {
+    let [label, rate, graph, info] = Layout::horizontal([Constraint::Length(28), Constraint::Length(18), Constraint::Min(10), Constraint::Length(46)]).areas(row);
+
+    frame.render_widget(Paragraph::new(meter.label.as_str()), label);
+    let current = history.rates.back().copied().unwrap_or(0);
+    frame.render_widget(Paragraph::new(format!("{:>11} MBit/s", format_mbit(current))), rate);
+    frame.render_widget(sparkline(&history.rates, graph.width).style(Style::default().fg(Color::Cyan)), graph);
+
+    let locale = Locale::de;
+    let text = history.tcp_info.map_or(String::new(), |info| {
+        format!(
+            " RTT {} ± {} ms  cwnd {}  Retrans {}",
+            format_number(info.rtt.as_secs_f64() * 1000.0, &locale),
+            format_number(info.rtt_var.as_secs_f64() * 1000.0, &locale),
+            info.cwnd,
+            info.retransmits
+        )
+    });
+    frame.render_widget(Paragraph::new(text), info);
+}
+
+/// Sparkline of the newest values that fit into `width`, since it draws from the left.
+fn sparkline(values: &VecDeque<u64>, width: u16) -> Sparkline<'static> {
+    let shown: Vec<u64> = values.iter().skip(values.len().saturating_sub(width as usize)).copied().collect();
+    Sparkline::default().data(&shown)
+}
+
+fn push(values: &mut VecDeque<u64>, value: u64) {
+    values.push_back(value);
+    if values.len() > HISTORY {
+        values.pop_front();
+    }
+}
+
+fn format_mbit(kbit: u64) -> String {
+    format_number(kbit as f64 / 1_000.0, &Locale::de)
+}
+
+fn format_time(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
+/// Captured output, the newest [`KEPT_LINES`] lines of it.
+#[derive(Debug, Default)]
+struct Log {
+    lines: VecDeque<String>,
+    dropped: usize,
+}
+
+impl Log {
+    fn push(&mut self, line: String) {
+        if self.lines.len() == KEPT_LINES {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        self.lines.push_back(line);
+    }
+
+    /// The newest `count` lines, oldest first.
+    fn last(&self, count: usize) -> impl Iterator<Item = &String> {
+        self.lines.iter().skip(self.lines.len().saturating_sub(count))
+    }
+
+    /// Empties the log, with a note on the lines that did not fit first.
+    fn take(&mut self) -> Vec<String> {
+        let dropped = std::mem::take(&mut self.dropped);
+        let note = (dropped > 0).then(|| format!("({} earlier lines not kept)", dropped));
+        note.into_iter().chain(self.lines.drain(..)).collect()
+    }
+}
+
+/// Redirects stdout and stderr into a pipe read line by line, keeping the originals for the terminal.
+#[cfg(unix)]
+struct Capture {
+    stdout: std::os::fd::OwnedFd,
+    stderr: std::os::fd::OwnedFd,
+    reader: JoinHandle<()>,
+    lines: Arc<Mutex<Log>>,
+}
+
+#[cfg(unix)]
+impl Capture {
+    fn start(lines: Arc<Mutex<Log>>) -> io::Result<Capture> {
+        use std::io::{BufRead, BufReader};
+        use std::os::fd::AsFd;
+
+        io::stdout().flush()?;
+        let stdout = io::stdout().as_fd().try_clone_to_owned()?;
+        let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+
+        let (reader, writer) = io::pipe()?;
+        redirect(writer.as_fd(), libc::STDOUT_FILENO)?;
+        redirect(writer.as_fd(), libc::STDERR_FILENO)?;
+        drop(writer);
+
+        let reader = thread::spawn({
+            let lines = Arc::clone(&lines);
+            move || {
+                for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                    lines.lock().unwrap().push(line);
+                }
+            }
+        });
+
+        Ok(Capture { stdout, stderr, reader, lines })
+    }
+
+    /// The original stdout, for drawing.
+    fn terminal(&self) -> io::Result<File> {
+        Ok(File::from(self.stdout.try_clone()?))
+    }
+
+    /// Puts the original stdout and stderr back and returns everything captured.
+    fn stop(self) -> Vec<String> {
+        use std::os::fd::AsFd;
+
+        let _ = io::stdout().flush();
+        let _ = redirect(self.stdout.as_fd(), libc::STDOUT_FILENO);
+        let _ = redirect(self.stderr.as_fd(), libc::STDERR_FILENO);
+        // The pipe closes with the last redirected descriptor, which ends the reader
+        let _ = self.reader.join();
+        self.lines.lock().unwrap().take()
+    }
+}
+
+#[cfg(unix)]
+fn redirect(from: std::os::fd::BorrowedFd, to: std::os::fd::RawFd) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: both descriptors are valid, `to` is one of the standard streams
+    match unsafe { libc::dup2(from.as_raw_fd(), to) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+struct Capture;
+
+#[cfg(not(unix))]
+impl Capture {
+    fn start(_lines: Arc<Mutex<Log>>) -> io::Result<Capture> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "The terminal UI needs Unix"))
+    }
+
+    fn terminal(&self) -> io::Result<File> {
+        unreachable!()
+    }
+
+    fn stop(self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log() {
+        let mut log = Log::default();
+        for i in 0..KEPT_LINES + 5 {
+            log.push(format!("line {}", i));
+        }
+        let newest: Vec<_> = log.last(2).map(String::as_str).collect();
+        assert_eq!(newest, [format!("line {}", KEPT_LINES + 3), format!("line {}", KEPT_LINES + 4)]);
+
+        let lines = log.take();
+        assert_eq!(lines.len(), KEPT_LINES + 1);
+        assert_eq!(lines[0], "(5 earlier lines not kept)");
+        assert_eq!(lines[1], "line 5");
+        assert!(log.take().is_empty());
+    }
+
+    #[test]
+    fn test_history_and_formats() {
+        let mut values = VecDeque::new();
+        for value in 0..HISTORY as u64 + 10 {
+            push(&mut values, value);
+        }
+        assert_eq!(values.len(), HISTORY);
+        assert_eq!(values.front(), Some(&10));
+        assert_eq!(format_mbit(1_234_500), "1.234,50");
+        assert_eq!(format_time(Duration::from_secs(754)), "12:34");
+    }
+}
@@ -1,10 +1,11 @@
 use crate::Direction;
-use chrono::Local;
+use chrono::{DateTime, Local};
 use num_format::{Locale, ToFormattedString};
-use std::io::Write;
+use std::io::{self, Write};
 use std::{fs::OpenOptions, io::BufWriter, path::Path};
-use tokio::time::Instant;
 
+/// Amount and throughput of a test in the units the results are printed in (decimal, 1 MByte = 10⁶ bytes).
+#[derive(Clone, Debug, PartialEq)]
 pub struct Statistics {
     pub duration: f64,
     pub total_bytes: usize,
@@ -22,41 +23,43 @@ pub struct Statistics {
     pub seconds: u64,
 }
 
-fn calculate_statistics(duration: f64, total_bytes: usize) -> Statistics {
-    let total_mbytes = total_bytes as f64 / 1_000_000.0;
-    let total_mbits = total_mbytes * 8.0;
-    let total_gbytes = total_mbytes / 1_000.0;
-    let total_gbits = total_mbits / 1_000.0;
-
-    let kbytes_per_sec = (total_bytes as f64 / 1_000.0) / duration;
-    let kbits_per_sec = kbytes_per_sec * 8.0;
-    let mbytes_per_sec = total_mbytes / duration;
-    let mbits_per_sec = total_mbits / duration;
-    let gbytes_per_sec = total_gbytes / duration;
-    let gbits_per_sec = total_gbits / duration;
-
-    let minutes = (duration as u64) / 60;
-    let seconds = (duration as u64) % 60;
-
-    Statistics {
-        duration,
-        total_bytes,
-        total_mbytes,
-        total_mbits,
-        total_gbytes,
-        total_gbits,
-        kbytes_per_sec,
-        kbits_per_sec,
-        mbytes_per_sec,
-        mbits_per_sec,
-        gbytes_per_sec,
-        gbits_per_sec,
-        minutes,
-        seconds,
+impl Statistics {
+    pub fn new(duration: f64, total_bytes: usize) -> Statistics {
+        let total_mbytes = total_bytes as f64 / 1_000_000.0;
+        let total_mbits = total_mbytes * 8.0;
+        let total_gbytes = total_mbytes / 1_000.0;
+        let total_gbits = total_mbits / 1_000.0;
+
+        let kbytes_per_sec = (total_bytes as f64 / 1_000.0) / duration;
+        let kbits_per_sec = kbytes_per_sec * 8.0;
+        let mbytes_per_sec = total_mbytes / duration;
+        let mbits_per_sec = total_mbits / duration;
+        let gbytes_per_sec = total_gbytes / duration;
+        let gbits_per_sec = total_gbits / duration;
+
+        let minutes = (duration as u64) / 60;
+        let seconds = (duration as u64) % 60;
+
+        Statistics {
+            duration,
+            total_bytes,
+            total_mbytes,
+            total_mbits,
+            total_gbytes,
+            total_gbits,
+            kbytes_per_sec,
+            kbits_per_sec,
+            mbytes_per_sec,
+            mbits_per_sec,
+            gbytes_per_sec,
+            gbits_per_sec,
+            minutes,
+            seconds,
+        }
     }
 }
 
-fn write_statistics_terminal(stats: &Statistics) {
+pub fn write_statistics_terminal(stats: &Statistics) {
     let locale = Locale::de;
 
     println!("• Dauer:");
@@ -78,22 +81,20 @@ fn write_statistics_terminal(stats: &Statistics) {
     println!("   - {} GBit/s", format_number(stats.gbits_per_sec, &locale));
 }
 
-fn write_statistics_csv(stats: &Statistics, direction: Direction, block_size_kb: usize, remote_addr: &str) {
+fn write_statistics_csv(csv_path: &Path, stats: &Statistics, timestamp: DateTime<Local>, direction: Direction, block_size_kb: usize, remote_addr: &str) -> io::Result<()> {
     let locale = Locale::de;
 
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-    let csv_path = Path::new("results.csv");
+    let timestamp = timestamp.format("%Y-%m-%d %H:%M:%S").to_string();
     let file_exists = csv_path.exists();
 
-    let file = OpenOptions::new().append(true).create(true).open(csv_path).expect("Unable to open or create CSV file");
+    let file = OpenOptions::new().append(true).create(true).open(csv_path)?;
     let mut writer = BufWriter::new(file);
 
     if !file_exists {
         writeln!(
             writer,
             "Zeitpunkt;Adresse;Richtung;Blockgröße (KB);Dauer (s);Gesamt MByte;Gesamt MBit;Gesamt GByte;Gesamt GBit;KByte/s;KBit/s;MByte/s;MBit/s;GByte/s;GBit/s"
-        )
-        .unwrap();
+        )?;
     }
 
     writeln!(
@@ -114,21 +115,19 @@ fn write_statistics_csv(stats: &Statistics, direction: Direction, block_size_kb:
         format_number(stats.mbits_per_sec, &locale),
         format_number(stats.gbytes_per_sec, &locale),
         format_number(stats.gbits_per_sec, &locale),
-    )
-    .unwrap();
+    )?;
 
-    writer.flush().expect("Failed to flush CSV writer");
+    writer.flush()
 }
 
 pub fn print_statistics_terminal(duration: f64, total_bytes: usize) {
-    let stats = calculate_statistics(duration, total_bytes);
+    let stats = Statistics::new(duration, total_bytes);
     write_statistics_terminal(&stats);
 }
 
-pub fn print_statistics(duration: f64, total_bytes: usize, direction: Direction, block_size_kb: usize, remote_addr: &str) {
-    let stats = calculate_statistics(duration, total_bytes);
-    write_statistics_terminal(&stats);
-    write_statistics_csv(&stats, direction, block_size_kb, remote_addr);
+pub fn append_statistics_csv(csv_path: &Path, timestamp: DateTime<Local>, duration: f64, total_bytes: usize, direction: Direction, block_size_kb: usize, remote_addr: &str) -> io::Result<()> {
+    let stats = Statistics::new(duration, total_bytes);
+    write_statistics_csv(csv_path, &stats, timestamp, direction, block_size_kb, remote_addr)
 }
 
 pub fn format_number(value: f64, locale: &Locale) -> String {
@@ -145,15 +144,30 @@ pub fn format_number(value: f64, locale: &Locale) -> String {
     format!("{}{}", formatted_whole, formatted_fraction)
 }
 
-pub fn format_duration_hms(start: Instant, end: Instant) -> String {
-    let duration = end.duration_since(start);
+/// Median of `values`, NaN if there are none.
+pub fn median(values: &[f64]) -> f64 {
+    let sorted = sorted(values);
+    match sorted.len() {
+        0 => f64::NAN,
+        n if n % 2 == 0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+        n => sorted[n / 2],
+    }
+}
 
-    let total_secs = duration.as_secs();
-    let hours = total_secs / 3600;
-    let minutes = (total_secs % 3600) / 60;
-    let seconds = total_secs % 60;
+/// Nearest-rank percentile of `values`, NaN if there are none.
+pub fn percentile(values: &[f64], percent: f64) -> f64 {
+    let sorted = sorted(values);
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
 
-    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+fn sorted(values: &[f64]) -> Vec<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted
 }
 
 pub fn generate_test_sizes(max_size: usize) -> Vec<usize> {
@@ -0,0 +1,313 @@
+//! Client and server in one process, over in-memory pipes and over loopback TCP.
+
+use speedtest::agent::{self, ControlOptions};
+use speedtest::auth::{Key, ServerAuth};
+use speedtest::clock::{Clock, ManualClock};
+use speedtest::limits::Limits;
+use speedtest::net::ConnectOptions;
+use speedtest::protocol::{Handshake, Transport, client_handshake, read_line};
+use speedtest::quic;
+use speedtest::tls::{self, Identity, TlsClientOptions, Verification};
+use speedtest::transport::{self, Connector, MemoryConnector};
+use speedtest::{Client, Direction, RunningServer, Server};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, timeout};
+
+/// Upper bound for one test, so a hanging server fails the test instead of blocking the suite.
+const TEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+async fn memory_server(server: Server) -> (RunningServer, MemoryConnector) {
+    let (connector, acceptor) = transport::memory(64 * 1024);
+    let server = server.start_with(acceptor).await.unwrap();
+    (server, connector)
+}
+
+/// Server on a free loopback port.
+fn loopback() -> Server {
+    Server::new().bind("127.0.0.1:0".parse().unwrap()).port(0)
+}
+
+fn client(address: &str, direction: Direction) -> Client {
+    Client::new(address).streams(3).block_size_kb(16).duration_secs(1).direction(direction)
+}
+
+/// Opens a raw stream and sends `request`, returning the server's first reply line.
+/// With `close` the client shuts down its side after the request.
+async fn send_raw(connector: &MemoryConnector, request: &str, close: bool) -> String {
+    let mut stream = connector.connect(0).await.unwrap().stream;
+    stream.write_all(request.as_bytes()).await.unwrap();
+    if close {
+        stream.shutdown().await.unwrap();
+    }
+    timeout(TEST_TIMEOUT, read_line(&mut stream)).await.unwrap().unwrap()
+}
+
+/// Runs a one-off test in `direction` and checks both sides counted the same bytes.
+async fn check_direction(server: RunningServer, client: Client, connector: Option<Arc<dyn Connector>>) {
+    let result = match connector {
+        Some(connector) => client.run_with(connector).await,
+        None => client.run().await,
+    };
+    let result = result.unwrap();
+    let measurement = &result.measurement;
+    assert_eq!(measurement.errors, 0);
+    assert_eq!(measurement.stream_bytes.len(), 3);
+    assert!(measurement.stream_bytes.iter().all(|bytes| *bytes > 0), "{:?}", measurement.stream_bytes);
+    assert_eq!(result.statistics.total_bytes, measurement.bytes);
+
+    let report = timeout(TEST_TIMEOUT, server.wait()).await.unwrap();
+    assert!(report.succeeded());
+    let session = report.session.unwrap();
+    assert_eq!(session.direction, measurement.direction);
+    assert_eq!((session.joined, session.failed), (3, 0));
+    assert_eq!(session.bytes, measurement.bytes, "{:?}", measurement.direction);
+}
+
+async fn check_memory(direction: Direction) {
+    let (server, connector) = memory_server(Server::new().one_off(true)).await;
+    let test = check_direction(server, client("memory", direction), Some(Arc::new(connector)));
+    timeout(TEST_TIMEOUT, test).await.unwrap();
+}
+
+async fn check_loopback(direction: Direction) {
+    let server = loopback().one_off(true).start().await.unwrap();
+    let address = server.local_addr().unwrap().to_string();
+    timeout(TEST_TIMEOUT, check_direction(server, client(&address, direction), None)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_memory_upload() {
+    check_memory(Direction::Upload).await;
+}
+
+#[tokio::test]
+async fn test_memory_download() {
+    check_memory(Direction::Download).await;
+}
+
+#[tokio::test]
+async fn test_memory_bidirectional() {
+    check_memory(Direction::Bidirectional).await;
+}
+
+#[tokio::test]
+async fn test_loopback_upload() {
+    check_loopback(Direction::Upload).await;
+}
+
+#[tokio::test]
+async fn test_loopback_download() {
+    check_loopback(Direction::Download).await;
+}
+
+#[tokio::test]
+async fn test_loopback_bidirectional() {
+    check_loopback(Direction::Bidirectional).await;
+}
+
+/// A 10 s test on a clock that moves 10 ms per reading takes a fraction of a second.
+#[tokio::test]
+async fn test_manual_clock() {
+    for direction in [Direction::Download, Direction::Bidirectional] {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::stepping(Duration::from_millis(10)));
+        let (server, connector) = memory_server(Server::new().one_off(true).clock(Arc::clone(&clock))).await;
+        let client = client("memory", direction).duration_secs(10).clock(clock);
+        let test = check_direction(server, client, Some(Arc::new(connector)));
+        timeout(Duration::from_secs(5), test).await.unwrap();
+    }
+}
+
+/// Writes a certificate for localhost and 127.0.0.1 and its key to PEM files, returning their paths.
+fn write_certificate(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let (cert, key) = (
+        dir.join(format!("speedtest-{}-{}.crt", name, std::process::id())),
+        dir.join(format!("speedtest-{}-{}.key", name, std::process::id())),
+    );
+    std::fs::write(&cert, generated.cert.pem()).unwrap();
+    std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+    (cert, key)
+}
+
+fn tls_options(verification: &Verification) -> ConnectOptions {
+    ConnectOptions {
+        tls: Some(TlsClientOptions {
+            config: tls::client_config(verification).unwrap(),
+            server_name: Some("localhost".to_string()),
+        }),
+        ..ConnectOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let (cert, key) = write_certificate("tls");
+    let config = tls::server_config(&Identity::load(&cert, &key).unwrap()).unwrap();
+    let server = loopback().tls(config).start().await.unwrap();
+    let address = server.local_addr().unwrap().to_string();
+
+    // The certificate is not signed by any of the web roots
+    let error = client(&address, Direction::Upload).connect_options(tls_options(&Verification::WebRoots)).run().await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{}", error);
+
+    for verification in [Verification::Ca(cert.clone()), Verification::Insecure] {
+        let client = client(&address, Direction::Download).connect_options(tls_options(&verification));
+        let result = timeout(TEST_TIMEOUT, client.run()).await.unwrap().unwrap();
+        assert_eq!(result.measurement.errors, 0);
+        let handshake = result.tls.unwrap();
+        assert_eq!(handshake.version, "TLSv1_3");
+    }
+
+    server.shutdown();
+    let _ = (std::fs::remove_file(cert), std::fs::remove_file(key));
+}
+
+/// Runs a one-off QUIC test in `direction`, all streams in one connection on the server's TCP port.
+async fn check_quic(direction: Direction) {
+    let (cert, key) = write_certificate(&format!("quic-{:?}", direction));
+    let config = quic::server_config(&Identity::load(&cert, &key).unwrap()).unwrap();
+    let server = loopback().quic(config).one_off(true).start().await.unwrap();
+    let address = server.local_addr().unwrap().to_string();
+
+    let options = ConnectOptions {
+        quic: Some(quic::client_config(&Verification::Ca(cert.clone()), Some("localhost".to_string())).unwrap()),
+        ..ConnectOptions::default()
+    };
+    timeout(TEST_TIMEOUT, check_direction(server, client(&address, direction).connect_options(options), None))
+        .await
+        .unwrap();
+    let _ = (std::fs::remove_file(cert), std::fs::remove_file(key));
+}
+
+#[tokio::test]
+async fn test_quic_upload() {
+    check_quic(Direction::Upload).await;
+}
+
+#[tokio::test]
+async fn test_quic_download() {
+    check_quic(Direction::Download).await;
+}
+
+#[tokio::test]
+async fn test_agent() {
+    let (cert, key) = write_certificate("agent");
+    let config = tls::server_config(&Identity::load(&cert, &key).unwrap()).unwrap();
+    let target = loopback().tls(config).start().await.unwrap();
+    let psk = Key::new(b"agents");
+    let agent_server = |verification| {
+        let auth = ServerAuth {
+            session: Some(psk.clone()),
+            admin: None,
+        };
+        let limits = Limits {
+            max_duration_secs: Some(2),
+            ..Limits::default()
+        };
+        loopback().auth(auth).limits(limits).agent(true).agent_verification(verification).start()
+    };
+    let control = |agent: &RunningServer, duration_secs| ControlOptions {
+        agent: agent.local_addr().unwrap().to_string(),
+        connect: ConnectOptions {
+            psk: Some(psk.clone()),
+            ..ConnectOptions::default()
+        },
+        target: target.local_addr().unwrap().to_string(),
+        transport: Transport::Tls,
+        threads: 2,
+        block_size_kb: 16,
+        duration_secs,
+        direction: Direction::Download,
+    };
+
+    // Run commands are limited like client sessions, and the agent checks the target's certificate
+    let trusting = agent_server(Verification::Ca(cert.clone())).await.unwrap();
+    assert!(agent::run_control(control(&trusting, 5)).await.is_none());
+    let measurement = timeout(TEST_TIMEOUT, agent::run_control(control(&trusting, 1))).await.unwrap().unwrap();
+    assert_eq!(measurement.errors, 0);
+    assert!(measurement.bytes > 0);
+
+    let strict = agent_server(Verification::WebRoots).await.unwrap();
+    assert!(timeout(TEST_TIMEOUT, agent::run_control(control(&strict, 1))).await.unwrap().is_none());
+
+    for server in [trusting, strict, target] {
+        server.shutdown();
+    }
+    let _ = (std::fs::remove_file(cert), std::fs::remove_file(key));
+}
+
+#[tokio::test]
+async fn test_handshake_errors() {
+    let (server, connector) = memory_server(Server::new()).await;
+
+    let reply = send_raw(&connector, "sideways\n", false).await;
+    assert_eq!(reply, "error unknown direction 'sideways'");
+    let reply = send_raw(&connector, "upload\nsoon\n", false).await;
+    assert_eq!(reply, "error invalid duration 'soon'");
+    let reply = send_raw(&connector, "upload\n5\nsession abc many\n", false).await;
+    assert_eq!(reply, "error invalid stream count 'many'");
+    let reply = send_raw(&connector, "run quit\n", false).await;
+    assert_eq!(reply, "error an agent cannot run quit");
+    // The client closing mid-request is an error too, not a test of 0 s
+    let reply = send_raw(&connector, "download\n", true).await;
+    assert_eq!(reply, "error missing duration line");
+
+    // Rejected requests leave the server running
+    assert_eq!(server.sessions().counts(), (0, 0));
+    let result = client("memory", Direction::Upload).run_with(Arc::new(connector)).await.unwrap();
+    assert_eq!(result.measurement.errors, 0);
+
+    server.shutdown();
+    assert!(timeout(TEST_TIMEOUT, server.wait()).await.unwrap().succeeded());
+}
+
+#[tokio::test]
+async fn test_wrong_key() {
+    let auth = ServerAuth {
+        session: Some(Key::new(b"right")),
+        admin: None,
+    };
+    let (server, connector) = memory_server(Server::new().auth(auth)).await;
+
+    let options = ConnectOptions {
+        psk: Some(Key::new(b"wrong")),
+        ..ConnectOptions::default()
+    };
+    let error = client("memory", Direction::Upload).connect_options(options).run_with(Arc::new(connector.clone())).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+
+    let error = client("memory", Direction::Upload).run_with(Arc::new(connector)).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    server.shutdown();
+}
+
+#[tokio::test]
+async fn test_quit() {
+    let admin = Key::new(b"admin");
+    let auth = ServerAuth {
+        session: None,
+        admin: Some(admin.clone()),
+    };
+    let (server, connector) = memory_server(Server::new().auth(auth)).await;
+
+    // Quit needs the admin key, the server only answers with a challenge
+    let reply = send_raw(&connector, "quit\n", false).await;
+    assert!(reply.starts_with("challenge "), "{}", reply);
+
+    // A test that runs while quit arrives may finish
+    let (running_client, running_connector) = (client("memory", Direction::Upload).duration_secs(2), Arc::new(connector.clone()));
+    let running = tokio::spawn(async move { running_client.run_with(running_connector).await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut stream = connector.connect(0).await.unwrap().stream;
+    let handshake = client_handshake(&mut stream, "quit\n", Some(&admin)).await.unwrap();
+    assert_eq!(handshake, Handshake::Accepted);
+
+    let report = timeout(TEST_TIMEOUT, server.wait()).await.unwrap();
+    assert!(report.succeeded());
+    let result = running.await.unwrap().unwrap();
+    assert_eq!(result.measurement.errors, 0);
+    assert_eq!(report.statistics.total_bytes, result.measurement.bytes);
+}
@@ -0,0 +1,126 @@
+//! Tests through the impairment relay in front of a loopback server.
+
+use speedtest::net::ConnectOptions;
+use speedtest::protocol::read_line;
+use speedtest::quic;
+use speedtest::relay::{Impairment, Relay, RelayOptions, UdpStats};
+use speedtest::tls::{Identity, Verification};
+use speedtest::{Client, Direction, RunningServer, Server};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant, timeout};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Starts `server` and a relay in front of it, returning the relay address and its UDP counters.
+async fn relay_to(server: Server, udp: bool, impairment: Impairment) -> (RunningServer, String, Arc<UdpStats>) {
+    let server = server.bind("127.0.0.1:0".parse().unwrap()).port(0).start().await.unwrap();
+    let relay = Relay::bind(RelayOptions {
+        bind: Some("127.0.0.1:0".parse().unwrap()),
+        port: 0,
+        target: server.local_addr().unwrap().to_string(),
+        udp,
+        impairment,
+        verbose: false,
+    })
+    .await
+    .unwrap();
+    let (address, stats) = (relay.local_addr().unwrap().to_string(), relay.udp_stats());
+    tokio::spawn(relay.run());
+    (server, address, stats)
+}
+
+/// A plain TCP server behind a relay.
+async fn relayed_server(impairment: Impairment) -> (RunningServer, String) {
+    let (server, address, _) = relay_to(Server::new(), false, impairment).await;
+    (server, address)
+}
+
+#[tokio::test]
+async fn test_delay() {
+    let impairment = Impairment {
+        delay: Duration::from_millis(100),
+        ..Impairment::default()
+    };
+    let (server, address) = relayed_server(impairment).await;
+
+    // Request and reply are delayed once each
+    let mut stream = TcpStream::connect(&address).await.unwrap();
+    let start = Instant::now();
+    stream.write_all(b"sideways\n").await.unwrap();
+    let reply = timeout(TEST_TIMEOUT, read_line(&mut stream)).await.unwrap().unwrap();
+    assert_eq!(reply, "error unknown direction 'sideways'");
+    assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+
+    let client = Client::new(&address).streams(2).block_size_kb(16).duration_secs(1).direction(Direction::Bidirectional);
+    let result = timeout(TEST_TIMEOUT, client.run()).await.unwrap().unwrap();
+    assert_eq!(result.measurement.errors, 0);
+    assert!(result.measurement.stream_bytes.iter().all(|bytes| *bytes > 0));
+    server.shutdown();
+}
+
+#[tokio::test]
+async fn test_bandwidth() {
+    let impairment = Impairment {
+        bandwidth_mbit: Some(16.0),
+        ..Impairment::default()
+    };
+    let (server, address) = relayed_server(impairment).await;
+
+    let client = Client::new(&address).streams(2).block_size_kb(16).duration_secs(2).direction(Direction::Download);
+    let result = timeout(TEST_TIMEOUT, client.run()).await.unwrap().unwrap();
+    assert_eq!(result.measurement.errors, 0);
+    // Both streams share the cap, what the relay still had queued at the deadline stretches the test
+    let mbits = result.statistics.mbits_per_sec;
+    assert!((12.0..20.0).contains(&mbits), "{} MBit/s", mbits);
+    server.shutdown();
+}
+
+#[tokio::test]
+async fn test_quic_loss_and_delay() {
+    let impairment = Impairment {
+        delay: Duration::from_millis(50),
+        loss: 0.05,
+        ..Impairment::default()
+    };
+    let identity = Identity::generate().unwrap();
+    let (server, address, stats) = relay_to(Server::new().quic(quic::server_config(&identity).unwrap()), true, impairment).await;
+
+    let options = ConnectOptions {
+        quic: Some(quic::client_config(&Verification::Insecure, None).unwrap()),
+        ..ConnectOptions::default()
+    };
+    let client = Client::new(&address)
+        .streams(2)
+        .block_size_kb(16)
+        .duration_secs(1)
+        .direction(Direction::Download)
+        .connect_options(options);
+    let result = timeout(TEST_TIMEOUT, client.run()).await.unwrap().unwrap();
+    // QUIC recovers the dropped datagrams, its round trip goes through the delay both ways.
+    // A download, as the loss slows QUIC down so much that an upload's backlog outlasts the grace period.
+    assert_eq!(result.measurement.errors, 0);
+    let latency_ms = result.measurement.latency_ms.unwrap();
+    assert!(latency_ms >= 100.0, "{} ms", latency_ms);
+    let (datagrams, lost) = (stats.datagrams.load(Ordering::Relaxed), stats.lost.load(Ordering::Relaxed));
+    assert!(lost > 0 && lost < datagrams / 5, "{} of {} datagrams lost", lost, datagrams);
+    server.shutdown();
+}
+
+#[tokio::test]
+async fn test_invalid_loss() {
+    for loss in [-0.1, 1.5, f64::NAN] {
+        let options = RelayOptions {
+            bind: Some("127.0.0.1:0".parse().unwrap()),
+            port: 0,
+            target: "127.0.0.1:1".to_string(),
+            udp: true,
+            impairment: Impairment { loss, ..Impairment::default() },
+            verbose: false,
+        };
+        let error = Relay::bind(options).await.err().expect("Invalid loss must be refused");
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "{}", loss);
+    }
+}